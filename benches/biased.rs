@@ -0,0 +1,49 @@
+//! Clone and drop of `BiasedArc` against `MyArc`, on the thread that created them and on another one.
+//!
+//! Run with `cargo bench --bench biased`.
+
+use my_arc::{BiasedArc, MyArc};
+use std::{hint::black_box, thread, time::Instant};
+
+const ITERATIONS: u32 = 10_000_000;
+
+fn bench(name: &str, f: impl FnOnce()) {
+    let start = Instant::now();
+    f();
+    let elapsed = start.elapsed();
+    println!("{:<32} {:>8.2} ns/iter", name, elapsed.as_nanos() as f64 / f64::from(ITERATIONS));
+}
+
+fn clone_drop<P: Clone>(p: &P) {
+    for _ in 0..ITERATIONS {
+        drop(black_box(p.clone()));
+    }
+}
+
+// Keeps a few handles alive, so that the counts don't go back to the same value at each iteration.
+fn clone_hold<P: Clone>(p: &P) {
+    let mut held = Vec::with_capacity(16);
+    for i in 0..ITERATIONS {
+        held.push(black_box(p.clone()));
+        if i % 16 == 15 {
+            held.clear();
+        }
+    }
+}
+
+fn main() {
+    let arc = MyArc::new(0u64);
+    let biased = BiasedArc::new(0u64);
+
+    bench("MyArc clone+drop", || clone_drop(&arc));
+    bench("BiasedArc clone+drop (owner)", || clone_drop(&biased));
+    bench("MyArc clone, hold 16", || clone_hold(&arc));
+    bench("BiasedArc clone, hold 16 (owner)", || clone_hold(&biased));
+
+    thread::scope(|s| {
+        s.spawn(|| bench("MyArc clone+drop (other)", || clone_drop(&arc)));
+    });
+    thread::scope(|s| {
+        s.spawn(|| bench("BiasedArc clone+drop (other)", || clone_drop(&biased)));
+    });
+}
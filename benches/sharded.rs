@@ -0,0 +1,30 @@
+//! Many threads cloning and dropping the same `ShardedArc`, against `MyArc`.
+//!
+//! Run with `cargo bench --bench sharded`.
+
+use my_arc::{MyArc, ShardedArc};
+use std::{hint::black_box, thread, time::Instant};
+
+const THREADS: usize = 64;
+const ITERATIONS: u32 = 200_000;
+
+fn bench<P: Clone + Send + Sync>(name: &str, p: &P) {
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                for _ in 0..ITERATIONS {
+                    drop(black_box(p.clone()));
+                }
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+    println!("{:<32} {:>8.2} ns/iter", name, elapsed.as_nanos() as f64 / f64::from(ITERATIONS));
+}
+
+fn main() {
+    let name = |ty| format!("{} clone+drop, {} threads", ty, THREADS);
+    bench(&name("MyArc"), &MyArc::new(0u64));
+    bench(&name("ShardedArc"), &ShardedArc::new(0u64));
+}
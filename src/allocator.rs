@@ -0,0 +1,75 @@
+use std::{alloc::{self, Layout}, error::Error, fmt, ptr::{self, NonNull}};
+
+/// The error returned by the `try_` constructors when the memory can't be allocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("memory allocation failed")
+    }
+}
+
+impl Error for AllocError {}
+
+/// Where `MyArc` gets its memory from, a stable version of the unstable `std::alloc::Allocator`.
+///
+/// Each `MyArc` and `MyWeak` keeps its own copy of the allocator (cloned from the one passed to
+/// `new_in`), and the last of them frees the block through it.
+///
+/// # Safety
+///
+/// A block returned by `allocate` must stay valid until it is passed to `deallocate` with the same
+/// layout, on this allocator or any clone of it.
+pub unsafe trait Allocator {
+    /// Allocates a block for `layout`, which is never zero-sized when called by `MyArc`.
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError>;
+
+    /// Same as `allocate`, with the block filled with zero bytes.
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        let ptr = self.allocate(layout)?;
+        unsafe { ptr::write_bytes(ptr.as_ptr(), 0, layout.size()) };
+        Ok(ptr)
+    }
+
+    /// Frees a block returned by `allocate`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `allocate` or `allocate_zeroed` of this allocator (or a clone of it)
+    /// with the same `layout`, and must not be used afterward.
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+}
+
+/// The global allocator, used by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Global;
+
+unsafe impl Allocator for Global {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        NonNull::new(unsafe { alloc::alloc(layout) }).ok_or(AllocError)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        NonNull::new(unsafe { alloc::alloc_zeroed(layout) }).ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        alloc::dealloc(ptr.as_ptr(), layout)
+    }
+}
+
+// Lets a MyArc borrow an allocator that lives longer than it, e.g. an arena.
+unsafe impl<A: Allocator + ?Sized> Allocator for &A {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        (**self).allocate(layout)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        (**self).allocate_zeroed(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        (**self).deallocate(ptr, layout)
+    }
+}
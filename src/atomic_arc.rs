@@ -0,0 +1,288 @@
+//! Slots holding a `MyArc` that can be read and replaced concurrently, e.g. a configuration that is
+//! reloaded while other threads use it.
+//!
+//! Reading the pointer and incrementing its rc are two separate steps, and in between a writer
+//! could swap the slot and drop the last `MyArc`, freeing the ArcInner before we increment. So the
+//! slot keeps its own `MyArc` alive until every reader that could have seen it is done:
+//! - a reader registers in `readers[epoch]`, loads the pointer, clones it and unregisters. It never
+//!   takes a lock, it only retries if the epoch changed while it was registering.
+//! - a writer (one at a time) swaps the pointer, flips the epoch so new readers register on the
+//!   other counter, waits for the old counter to drain, and only then drops the old `MyArc`.
+
+use crate::MyArc;
+use std::{marker::PhantomData, mem, ptr, sync::{atomic::{self, AtomicPtr, AtomicUsize}, Mutex, PoisonError}, thread};
+
+/// A slot holding an optional `MyArc<T>`, which can be loaded and replaced concurrently.
+pub struct AtomicOptionMyArc<T> {
+    // MyArc::into_raw of the current value, or null for None. The slot owns one rc of it.
+    ptr: AtomicPtr<T>,
+    // Number of readers between registering and cloning, for each epoch.
+    readers: [AtomicUsize; 2],
+    // Only the lowest bit is used, it selects the counter readers register on.
+    epoch: AtomicUsize,
+    // Writers wait for the readers of the previous epoch, which only works one writer at a time.
+    writer: Mutex<()>,
+    // As if we own a MyArc<T>, so we are Send and Sync only if it is.
+    _marker: PhantomData<MyArc<T>>
+}
+
+impl<T> AtomicOptionMyArc<T> {
+    pub fn new(arc: Option<MyArc<T>>) -> Self {
+        AtomicOptionMyArc {
+            ptr: AtomicPtr::new(into_ptr(arc)),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            epoch: AtomicUsize::new(0),
+            writer: Mutex::new(()),
+            _marker: PhantomData
+        }
+    }
+
+    /// Returns a clone of the current value. This never blocks, even while a writer is waiting.
+    pub fn load(&self) -> Option<MyArc<T>> {
+        loop {
+            // SeqCst everywhere on this path: the writer must see our registration if we could
+            // have loaded the pointer it swapped out, which needs a single order of the increment,
+            // the pointer load, the swap, the epoch flip and the writer's check of the counter.
+            let epoch = self.epoch.load(atomic::Ordering::SeqCst) & 1;
+            self.readers[epoch].fetch_add(1, atomic::Ordering::SeqCst);
+            // If the epoch was flipped in between, the writer may have checked our counter before we
+            // incremented it and won't wait for us.
+            if self.epoch.load(atomic::Ordering::SeqCst) & 1 != epoch {
+                self.readers[epoch].fetch_sub(1, atomic::Ordering::Release);
+                continue;
+            }
+
+            let ptr = self.ptr.load(atomic::Ordering::SeqCst);
+            let arc = unsafe {
+                // The slot still owns one rc of ptr, it is only dropped after we unregister.
+                if !ptr.is_null() {
+                    MyArc::increment_strong_count(ptr);
+                }
+                from_ptr(ptr)
+            };
+            // Release so that the writer, once it sees the counter drained, also sees our increment
+            // of rc before it drops its own MyArc.
+            self.readers[epoch].fetch_sub(1, atomic::Ordering::Release);
+            return arc;
+        }
+    }
+
+    /// Replaces the current value with `arc`.
+    pub fn store(&self, arc: Option<MyArc<T>>) {
+        drop(self.swap(arc));
+    }
+
+    /// Replaces the current value with `arc` and returns the previous one.
+    ///
+    /// This waits for the readers that may still be cloning the previous value.
+    pub fn swap(&self, arc: Option<MyArc<T>>) -> Option<MyArc<T>> {
+        let new = into_ptr(arc);
+        let old = {
+            let _writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+            let old = self.ptr.swap(new, atomic::Ordering::SeqCst);
+            self.wait_for_readers();
+            old
+        };
+        unsafe { from_ptr(old) }
+    }
+
+    /// Replaces the current value with `new` if it is the same allocation as `current`, and returns
+    /// the previous value. Otherwise nothing changes and `new` is given back as the error.
+    pub fn compare_and_swap(&self, current: Option<&MyArc<T>>, new: Option<MyArc<T>>) -> Result<Option<MyArc<T>>, Option<MyArc<T>>> {
+        let current = current.map_or(ptr::null(), MyArc::as_ptr) as *mut T;
+        let old = {
+            let _writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+            // Writers are serialized by the lock, so the pointer can't change until we swap it.
+            if self.ptr.load(atomic::Ordering::Relaxed) != current {
+                return Err(new);
+            }
+            self.ptr.store(into_ptr(new), atomic::Ordering::SeqCst);
+            self.wait_for_readers();
+            current
+        };
+        Ok(unsafe { from_ptr(old) })
+    }
+
+    /// Returns the current value, no other thread can be using the slot.
+    pub fn into_inner(mut self) -> Option<MyArc<T>> {
+        // Leave null behind, so that drop has nothing to release.
+        unsafe { from_ptr(mem::replace(self.ptr.get_mut(), ptr::null_mut())) }
+    }
+
+    // Must be called with the writer lock held, after the pointer was swapped.
+    fn wait_for_readers(&self) {
+        // Readers registering after the flip go to the other counter, and see the new pointer. The
+        // ones on the previous counter may have loaded the old one, so we wait for them to finish.
+        let epoch = self.epoch.fetch_xor(1, atomic::Ordering::SeqCst) & 1;
+        while self.readers[epoch].load(atomic::Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+    }
+}
+
+impl<T> Default for AtomicOptionMyArc<T> {
+    fn default() -> Self {
+        AtomicOptionMyArc::new(None)
+    }
+}
+
+impl<T> From<Option<MyArc<T>>> for AtomicOptionMyArc<T> {
+    fn from(arc: Option<MyArc<T>>) -> Self {
+        AtomicOptionMyArc::new(arc)
+    }
+}
+
+impl<T> Drop for AtomicOptionMyArc<T> {
+    fn drop(&mut self) {
+        // &mut self, so there are no readers left.
+        unsafe { drop(from_ptr(*self.ptr.get_mut())) }
+    }
+}
+
+/// A slot holding a `MyArc<T>`, which can be loaded and replaced concurrently. It is the same as
+/// `AtomicOptionMyArc`, without the `None`.
+pub struct AtomicMyArc<T> {
+    // Never holds None.
+    inner: AtomicOptionMyArc<T>
+}
+
+impl<T> AtomicMyArc<T> {
+    pub fn new(arc: MyArc<T>) -> Self {
+        AtomicMyArc { inner: AtomicOptionMyArc::new(Some(arc)) }
+    }
+
+    /// Returns a clone of the current value. This never blocks, even while a writer is waiting.
+    pub fn load(&self) -> MyArc<T> {
+        unwrap(self.inner.load())
+    }
+
+    /// Replaces the current value with `arc`.
+    pub fn store(&self, arc: MyArc<T>) {
+        self.inner.store(Some(arc))
+    }
+
+    /// Replaces the current value with `arc` and returns the previous one.
+    pub fn swap(&self, arc: MyArc<T>) -> MyArc<T> {
+        unwrap(self.inner.swap(Some(arc)))
+    }
+
+    /// Replaces the current value with `new` if it is the same allocation as `current`, and returns
+    /// the previous value. Otherwise nothing changes and `new` is given back as the error.
+    pub fn compare_and_swap(&self, current: &MyArc<T>, new: MyArc<T>) -> Result<MyArc<T>, MyArc<T>> {
+        self.inner.compare_and_swap(Some(current), Some(new)).map(unwrap).map_err(unwrap)
+    }
+
+    /// Returns the current value, no other thread can be using the slot.
+    pub fn into_inner(self) -> MyArc<T> {
+        unwrap(self.inner.into_inner())
+    }
+}
+
+impl<T: Default> Default for AtomicMyArc<T> {
+    fn default() -> Self {
+        AtomicMyArc::new(MyArc::new(T::default()))
+    }
+}
+
+impl<T> From<MyArc<T>> for AtomicMyArc<T> {
+    fn from(arc: MyArc<T>) -> Self {
+        AtomicMyArc::new(arc)
+    }
+}
+
+fn into_ptr<T>(arc: Option<MyArc<T>>) -> *mut T {
+    arc.map_or(ptr::null_mut(), |arc| MyArc::into_raw(arc) as *mut T)
+}
+
+// Takes back the rc owned by the slot.
+unsafe fn from_ptr<T>(ptr: *mut T) -> Option<MyArc<T>> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(MyArc::from_raw(ptr))
+    }
+}
+
+fn unwrap<T>(arc: Option<MyArc<T>>) -> MyArc<T> {
+    match arc {
+        Some(arc) => arc,
+        None => unreachable!("AtomicMyArc holds None")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::DropCounts, AtomicMyArc, AtomicOptionMyArc, MyArc};
+
+    #[test]
+    fn test_atomic_my_arc() {
+        let a = MyArc::new(1);
+        let slot = AtomicMyArc::new(a.clone());
+        assert_eq!(*slot.load(), 1);
+        assert_eq!(MyArc::strong_count(&a), 2);
+
+        let b = MyArc::new(2);
+        // a is still the current value, so the swap succeeds and gives it back.
+        let old = slot.compare_and_swap(&a, b.clone()).ok().unwrap();
+        assert_eq!(MyArc::as_ptr(&old), MyArc::as_ptr(&a));
+        // Now it fails and gives back the new value.
+        let c = slot.compare_and_swap(&a, MyArc::new(3)).err().unwrap();
+        assert_eq!(*c, 3);
+
+        assert_eq!(*slot.swap(c), 2);
+        slot.store(MyArc::new(4));
+        assert_eq!(*slot.into_inner(), 4);
+        drop(old);
+        assert_eq!(MyArc::strong_count(&a), 1);
+        assert_eq!(MyArc::strong_count(&b), 1);
+    }
+
+    #[test]
+    fn test_atomic_option_my_arc() {
+        let slot = AtomicOptionMyArc::default();
+        assert!(slot.load().is_none());
+        let a = MyArc::new(String::from("a"));
+        assert!(slot.compare_and_swap(None, Some(a.clone())).ok().unwrap().is_none());
+        assert_eq!(*slot.load().unwrap(), "a");
+        assert!(slot.swap(None).is_some());
+        assert_eq!(MyArc::strong_count(&a), 1);
+        slot.store(Some(a.clone()));
+        drop(slot);
+        assert_eq!(MyArc::strong_count(&a), 1);
+    }
+
+    #[test]
+    fn test_atomic_my_arc_threads() {
+        static COUNTS: DropCounts = DropCounts::new();
+        let slot = MyArc::new(AtomicMyArc::new(MyArc::new(COUNTS.value(0))));
+        let readers: Vec<_> = (0..4).map(|_| {
+            let slot = slot.clone();
+            std::thread::spawn(move || {
+                let mut last = 0;
+                for _ in 0..10_000 {
+                    let n = slot.load().get();
+                    // Writers only store increasing values.
+                    assert!(n >= last);
+                    last = n;
+                }
+            })
+        }).collect();
+        let writers: Vec<_> = (0..2).map(|_| {
+            let slot = slot.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1_000 {
+                    let current = slot.load();
+                    let next = MyArc::new(COUNTS.value(current.get() + 1));
+                    let _ = slot.compare_and_swap(&current, next);
+                }
+            })
+        }).collect();
+        for handle in readers.into_iter().chain(writers) {
+            handle.join().unwrap();
+        }
+
+        drop(slot);
+        assert!(COUNTS.all_dropped());
+    }
+}
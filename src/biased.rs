@@ -0,0 +1,396 @@
+//! Biased reference counting: the thread that creates a `BiasedArc` (its owner) counts its handles
+//! with plain arithmetic, and only the other threads pay for atomic operations.
+//!
+//! The count is split in two, `biased` which only the owner touches, and `shared` for everybody
+//! else, and the number of handles is their sum. Handles move freely between threads, so either
+//! counter may be decremented for a handle that incremented the other one:
+//! - `shared` may go below 0. The thread that takes it below 0 sets `QUEUED` with the same
+//!   compare-exchange, and hands the allocation to the owner (its queue), which merges the counts
+//!   the next time it drops a `BiasedArc`: it adds `biased` to `shared` and sets `MERGED`, after
+//!   which `shared` alone is the count, for every thread. The last decrement frees the allocation.
+//! - when `biased` reaches 0 the owner merges the counts too, unless `QUEUED` is set, in which
+//!   case the queue does it. The allocation is never freed while it is in the queue.
+//! - when the owner thread exits it merges what is in its queue, and from then on the thread that
+//!   sets `QUEUED` merges the counts itself.
+//!
+//! This is the scheme of "Biased Reference Counting" (Choi, Shull and Torrellas, PACT 2018).
+
+use crate::{MyArc, MAX_REFCOUNT};
+use std::{cell::Cell, mem, ops::Deref, ptr::NonNull, sync::{atomic::{self, AtomicBool, AtomicUsize}, Mutex, PoisonError}};
+
+// The flags in the lowest bits of shared, and the count in the other ones, signed.
+const MERGED: usize = 1;
+const QUEUED: usize = 2;
+const ONE: usize = 4;
+
+// The count in shared, negative when other threads dropped more handles than they cloned.
+fn shared_count(shared: usize) -> isize {
+    shared as isize >> 2
+}
+
+/// A reference-counted pointer optimized for handles cloned and dropped on the thread that created
+/// it, see src/biased.rs for how the counts work.
+///
+/// It has no weak handles, and overflowing a count always aborts, whatever the overflow features.
+///
+/// Unlike `MyArc`, the data is not always dropped by the last handle: when that handle is dropped
+/// on another thread than the owner, the data is dropped the next time the owner thread drops a
+/// `BiasedArc`, or when it exits. That is why `T` must be `'static`, it may outlive every handle:
+///
+/// ```compile_fail
+/// let n = 5;
+/// let _ = my_arc::BiasedArc::new(&n);
+/// ```
+pub struct BiasedArc<T: 'static> {
+    ptr: NonNull<BiasedInner<T>>
+}
+
+struct BiasedInner<T> {
+    // Keeps the owner record alive after the owner thread exits, and identifies the owner.
+    owner: MyArc<Owner>,
+    // Only touched by the owner thread, or by the thread merging the counts once it exited.
+    biased: Cell<usize>,
+    // count * ONE | QUEUED | MERGED.
+    shared: AtomicUsize,
+    data: T
+}
+
+// The same bounds as MyArc, biased is protected by the protocol above.
+unsafe impl<T: Send + Sync + 'static> Send for BiasedArc<T> {}
+unsafe impl<T: Send + Sync + 'static> Sync for BiasedArc<T> {}
+
+// A thread, as the owner of BiasedArc.
+struct Owner {
+    queue: Mutex<Queue>,
+    // Whether queue.handles is not empty, checked by the owner without locking.
+    pending: AtomicBool
+}
+
+struct Queue {
+    handles: Vec<Queued>,
+    exited: bool
+}
+
+// An allocation with QUEUED set, for the owner to merge its counts.
+struct Queued {
+    ptr: *const (),
+    // merge_queued for the erased type.
+    merge: unsafe fn(*const ())
+}
+
+// The handles are of Send + Sync types.
+unsafe impl Send for Queued {}
+
+// The Owner of the current thread.
+struct Current(MyArc<Owner>);
+
+thread_local! {
+    static CURRENT: Current = Current(MyArc::new(Owner::new(false)));
+}
+
+impl Owner {
+    fn new(exited: bool) -> Owner {
+        Owner { queue: Mutex::new(Queue { handles: Vec::new(), exited }), pending: AtomicBool::new(false) }
+    }
+
+    // Merges the counts of the allocations other threads queued.
+    fn release_queued(&self) {
+        let handles = {
+            let mut queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+            self.pending.store(false, atomic::Ordering::Relaxed);
+            mem::take(&mut queue.handles)
+        };
+        for queued in handles {
+            unsafe { (queued.merge)(queued.ptr) };
+        }
+    }
+}
+
+impl Drop for Current {
+    fn drop(&mut self) {
+        // From now on, the threads that set QUEUED merge the counts themselves.
+        let handles = {
+            let mut queue = self.0.queue.lock().unwrap_or_else(PoisonError::into_inner);
+            queue.exited = true;
+            mem::take(&mut queue.handles)
+        };
+        for queued in handles {
+            unsafe { (queued.merge)(queued.ptr) };
+        }
+    }
+}
+
+impl<T: 'static> BiasedArc<T> {
+    pub fn new(data: T) -> Self {
+        // A thread whose thread locals are already destroyed owns nothing, as if it exited.
+        let owner = CURRENT.try_with(|current| current.0.clone()).unwrap_or_else(|_| MyArc::new(Owner::new(true)));
+        let inner = Box::new(BiasedInner {
+            owner,
+            biased: Cell::new(1),
+            shared: AtomicUsize::new(0),
+            data
+        });
+        BiasedArc { ptr: NonNull::from(Box::leak(inner)) }
+    }
+
+    fn inner(&self) -> &BiasedInner<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    // Whether the current thread is the owner, which can't change while it runs.
+    fn is_owner(&self) -> bool {
+        let owner = MyArc::as_ptr(&self.inner().owner);
+        CURRENT.try_with(|current| MyArc::as_ptr(&current.0) == owner).unwrap_or(false)
+    }
+
+    fn increment_shared(&self) {
+        let old = self.inner().shared.fetch_add(ONE, atomic::Ordering::Relaxed);
+        if shared_count(old) >= (MAX_REFCOUNT / ONE) as isize {
+            std::process::abort();
+        }
+    }
+
+    // The owner's side of a drop, when biased is not 0.
+    fn release_biased(&self) {
+        let inner = self.inner();
+        let biased = match inner.biased.get().checked_sub(1) {
+            Some(biased) => biased,
+            None => {
+                // The owner only takes this path when biased is not 0, the handle counts in shared.
+                debug_assert!(false, "biased count already 0");
+                return self.release_shared();
+            }
+        };
+        inner.biased.set(biased);
+        if biased != 0 {
+            return;
+        }
+        let mut cur = inner.shared.load(atomic::Ordering::Relaxed);
+        loop {
+            if cur & QUEUED != 0 {
+                // Other threads took shared below 0, the queue merges the counts.
+                return;
+            }
+            // Acquire for the decrements of shared by other threads, and Release for ours of
+            // biased, so that whichever thread frees sees all the uses of the data.
+            match inner.shared.compare_exchange_weak(cur, cur | MERGED, atomic::Ordering::AcqRel, atomic::Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => cur = actual
+            }
+        }
+        // Without QUEUED shared is not below 0, and the handles left all count in it.
+        if shared_count(cur) == 0 {
+            unsafe { drop(Box::from_raw(self.ptr.as_ptr())) };
+        }
+    }
+
+    // Adds biased to shared and sets MERGED, for an allocation with QUEUED set. Only the thread
+    // that owns the queue entry calls it, which is the owner, or any thread once it exited.
+    unsafe fn merge_queued(ptr: *const ()) {
+        let inner = &*(ptr as *const BiasedInner<T>);
+        let biased = inner.biased.replace(0);
+        let mut cur = inner.shared.load(atomic::Ordering::Relaxed);
+        loop {
+            debug_assert!(cur & QUEUED != 0 && cur & MERGED == 0);
+            let new = (cur.wrapping_add(biased * ONE) & !QUEUED) | MERGED;
+            // Same as in release_biased.
+            match inner.shared.compare_exchange_weak(cur, new, atomic::Ordering::AcqRel, atomic::Ordering::Relaxed) {
+                Ok(_) => {
+                    debug_assert!(shared_count(new) >= 0);
+                    if shared_count(new) == 0 {
+                        drop(Box::from_raw(ptr as *mut BiasedInner<T>));
+                    }
+                    return;
+                }
+                Err(actual) => cur = actual
+            }
+        }
+    }
+
+    // A drop by another thread, or by the owner once biased is 0.
+    fn release_shared(&self) {
+        let inner = self.inner();
+        let mut cur = inner.shared.load(atomic::Ordering::Relaxed);
+        loop {
+            if cur & MERGED != 0 {
+                // Merged is never unset, shared is the count now. Same as `Drop for MyArc`.
+                if shared_count(inner.shared.fetch_sub(ONE, atomic::Ordering::Release)) == 1 {
+                    atomic::fence(atomic::Ordering::Acquire);
+                    unsafe { drop(Box::from_raw(self.ptr.as_ptr())) };
+                }
+                return;
+            }
+            // The first thread to take shared below 0 queues the allocation. Checking MERGED and
+            // setting QUEUED in one compare-exchange orders it with the merge in release_biased.
+            let queue = cur & QUEUED == 0 && shared_count(cur) <= 0;
+            let new = cur.wrapping_sub(ONE) | if queue { QUEUED } else { 0 };
+            match inner.shared.compare_exchange_weak(cur, new, atomic::Ordering::Release, atomic::Ordering::Relaxed) {
+                Ok(_) => {
+                    if queue {
+                        self.queue_to_owner();
+                    }
+                    return;
+                }
+                Err(actual) => cur = actual
+            }
+        }
+    }
+
+    fn queue_to_owner(&self) {
+        let ptr = self.ptr.as_ptr() as *const ();
+        let exited = {
+            let owner = &self.inner().owner;
+            let mut queue = owner.queue.lock().unwrap_or_else(PoisonError::into_inner);
+            if !queue.exited {
+                queue.handles.push(Queued { ptr, merge: BiasedArc::<T>::merge_queued });
+                owner.pending.store(true, atomic::Ordering::Relaxed);
+            }
+            queue.exited
+        };
+        // Outside of the lock, freeing may drop other handles of the same owner. The lock made the
+        // last updates of biased by the owner visible.
+        if exited {
+            unsafe { BiasedArc::<T>::merge_queued(ptr) };
+        }
+    }
+}
+
+impl<T: 'static> Deref for BiasedArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner().data
+    }
+}
+
+impl<T: 'static> Clone for BiasedArc<T> {
+    fn clone(&self) -> Self {
+        let inner = self.inner();
+        // Once biased reached 0 the counts are merged (or about to be), and the owner uses shared
+        // like the others.
+        if self.is_owner() && inner.biased.get() > 0 {
+            let biased = inner.biased.get();
+            // So that merging can add biased * ONE to shared.
+            if biased >= MAX_REFCOUNT / ONE {
+                std::process::abort();
+            }
+            inner.biased.set(biased + 1);
+        } else {
+            self.increment_shared();
+        }
+        BiasedArc { ptr: self.ptr }
+    }
+}
+
+impl<T: 'static> Drop for BiasedArc<T> {
+    fn drop(&mut self) {
+        if self.is_owner() {
+            let owner = &self.inner().owner;
+            if owner.pending.load(atomic::Ordering::Relaxed) {
+                owner.release_queued();
+            }
+            // The queue may have merged the counts, biased is read again after it.
+            if self.inner().biased.get() > 0 {
+                self.release_biased();
+                return;
+            }
+        }
+        self.release_shared();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::DropFlag, BiasedArc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_biased_owner() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let a = BiasedArc::new(DropFlag(&DROPS));
+        let b = a.clone();
+        drop(a);
+        let c = b.clone();
+        drop(b);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+        drop(c);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+
+        // The last handle dropped on another thread leaves the data to the owner.
+        let a = BiasedArc::new(DropFlag(&DROPS));
+        let b = a.clone();
+        drop(a);
+        std::thread::spawn(move || drop(b)).join().unwrap();
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+        drop(BiasedArc::new(()));
+        assert_eq!(DROPS.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_biased_threads() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let a = BiasedArc::new(DropFlag(&DROPS));
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                // Cloned here, dropped there, and the other way around.
+                let b = a.clone();
+                s.spawn(move || {
+                    let handles: Vec<_> = (0..100).map(|_| b.clone()).collect();
+                    drop(b);
+                    drop(handles);
+                });
+                let b = std::thread::spawn({
+                    let a = a.clone();
+                    move || a.clone()
+                }).join().unwrap();
+                drop(b);
+            }
+        });
+        assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+        drop(a);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_biased_owner_exits() {
+        // The only handle leaves its owner, which has to merge the counts when it exits.
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let (send, recv) = std::sync::mpsc::channel();
+        let owner = std::thread::spawn(move || {
+            let a = BiasedArc::new(DropFlag(&DROPS));
+            send.send(a.clone()).unwrap();
+            send.send(a).unwrap();
+        });
+        let (a, b) = (recv.recv().unwrap(), recv.recv().unwrap());
+        drop(a);
+        owner.join().unwrap();
+        assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+        drop(b);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_biased_queue_race() {
+        // Other threads take shared below 0 and queue the allocation, while a third one clones and
+        // drops and the owner drops its last handle, so that queuing races with the merge.
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        for round in 0..500 {
+            let a = BiasedArc::new(DropFlag(&DROPS));
+            let (b, c, d) = (a.clone(), a.clone(), a.clone());
+            std::thread::scope(|s| {
+                s.spawn(move || drop(b));
+                s.spawn(move || drop(c));
+                s.spawn(move || {
+                    for _ in 0..10 {
+                        drop(d.clone());
+                        std::thread::yield_now();
+                    }
+                });
+                drop(a);
+            });
+            // What was queued is merged the next time the owner drops a BiasedArc.
+            drop(BiasedArc::new(()));
+            assert_eq!(DROPS.load(Ordering::Relaxed), round + 1);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! `MyArcBorrow`, a `MyArc` borrowed without the double indirection of `&MyArc<T>`.
+
+use crate::{ArcInner, MyArc};
+use std::{marker::PhantomData, mem::ManuallyDrop, ops::Deref, ptr::NonNull};
+
+/// A borrowed `MyArc`, returned by `MyArc::borrow_arc`.
+///
+/// It points straight at the allocation like a `MyArc`, but doesn't own an rc, so copying and
+/// dropping it is free. `clone_arc` turns it into a new `MyArc`.
+pub struct MyArcBorrow<'a, T: ?Sized> {
+    ptr: NonNull<ArcInner<T>>,
+    // Borrows the MyArc it comes from, which keeps rc above 0 for 'a.
+    _marker: PhantomData<&'a MyArc<T>>
+}
+
+// The same bounds as &MyArc<T>.
+unsafe impl<T: ?Sized + Send + Sync> Send for MyArcBorrow<'_, T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for MyArcBorrow<'_, T> {}
+
+impl<T: ?Sized> MyArc<T> {
+    /// Borrows the `MyArc` as a `MyArcBorrow`, without touching rc.
+    pub fn borrow_arc(&self) -> MyArcBorrow<'_, T> {
+        MyArcBorrow { ptr: self.ptr, _marker: PhantomData }
+    }
+}
+
+impl<'a, T: ?Sized> MyArcBorrow<'a, T> {
+    /// Returns a new `MyArc` to the data, incrementing rc.
+    pub fn clone_arc(&self) -> MyArc<T> {
+        // The MyArc it borrows, which must not be dropped.
+        let arc = ManuallyDrop::new(unsafe { MyArc::from_inner(self.ptr.as_ptr()) });
+        MyArc::clone(&arc)
+    }
+
+    /// Returns the data, for as long as the `MyArc` is borrowed rather than as long as `self`.
+    pub fn get(&self) -> &'a T {
+        unsafe { &(*self.ptr.as_ptr()).data }
+    }
+}
+
+impl<T: ?Sized> Clone for MyArcBorrow<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for MyArcBorrow<'_, T> {}
+
+impl<T: ?Sized> Deref for MyArcBorrow<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.get()
+    }
+}
+
+impl<'a, T: ?Sized> From<&'a MyArc<T>> for MyArcBorrow<'a, T> {
+    fn from(arc: &'a MyArc<T>) -> Self {
+        arc.borrow_arc()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use crate::{MyArc, MyArcBorrow};
+
+    #[test]
+    fn test_borrow_arc() {
+        // Returns a reference that outlives the MyArcBorrow, tied to the MyArc instead.
+        fn first(b: MyArcBorrow<'_, [String]>) -> &str {
+            &b.get()[0]
+        }
+
+        let a: MyArc<[String]> = MyArc::from(vec![String::from("x"), String::from("y")]);
+        let b = a.borrow_arc();
+        let c = b;
+        assert_eq!(first(c), "x");
+        assert_eq!(b.len(), 2);
+        assert_eq!(MyArc::strong_count(&a), 1);
+
+        let d = c.clone_arc();
+        assert_eq!(MyArc::strong_count(&a), 2);
+        assert_eq!(MyArc::as_ptr(&d), MyArc::as_ptr(&a));
+        drop(d);
+        assert_eq!(MyArc::strong_count(&MyArcBorrow::from(&a).clone_arc()), 2);
+        assert_eq!(MyArc::strong_count(&a), 1);
+    }
+}
@@ -0,0 +1,69 @@
+//! `ByAddress`, to compare and hash `MyArc` by the allocation they point to instead of the data.
+
+use crate::{Allocator, Count, SharedPtr};
+use std::{cmp::Ordering, fmt, hash::{Hash, Hasher}, ops::Deref};
+
+/// Wraps a `MyArc` (or `MyRc`) so that `Eq`, `Ord` and `Hash` use the address of its allocation,
+/// e.g. to key a map by identity: two clones are equal, two `MyArc` with equal data are not.
+///
+/// The order between addresses is arbitrary, but stays the same as long as both are alive.
+pub struct ByAddress<P>(pub P);
+
+impl<T: ?Sized, C: Count, A: Allocator> ByAddress<SharedPtr<T, C, A>> {
+    fn addr(&self) -> usize {
+        // The ArcInner, without the length or vtable.
+        self.0.ptr.as_ptr() as *const u8 as usize
+    }
+}
+
+impl<P> Deref for ByAddress<P> {
+    type Target = P;
+
+    fn deref(&self) -> &P {
+        &self.0
+    }
+}
+
+impl<P> From<P> for ByAddress<P> {
+    fn from(p: P) -> Self {
+        ByAddress(p)
+    }
+}
+
+impl<P: Clone> Clone for ByAddress<P> {
+    fn clone(&self) -> Self {
+        ByAddress(self.0.clone())
+    }
+}
+
+impl<P: fmt::Debug> fmt::Debug for ByAddress<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ByAddress").field(&self.0).finish()
+    }
+}
+
+impl<T: ?Sized, C: Count, A: Allocator> PartialEq for ByAddress<SharedPtr<T, C, A>> {
+    fn eq(&self, other: &Self) -> bool {
+        SharedPtr::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<T: ?Sized, C: Count, A: Allocator> Eq for ByAddress<SharedPtr<T, C, A>> {}
+
+impl<T: ?Sized, C: Count, A: Allocator> PartialOrd for ByAddress<SharedPtr<T, C, A>> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: ?Sized, C: Count, A: Allocator> Ord for ByAddress<SharedPtr<T, C, A>> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.addr().cmp(&other.addr())
+    }
+}
+
+impl<T: ?Sized, C: Count, A: Allocator> Hash for ByAddress<SharedPtr<T, C, A>> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.addr().hash(state)
+    }
+}
@@ -0,0 +1,125 @@
+use crate::sync::atomic::{self, AtomicUsize};
+use std::cell::Cell;
+
+/// How the counts of an `ArcInner` are stored and updated, which is the only difference between
+/// `MyArc` (`AtomicUsize`) and `MyRc` (`Cell<usize>`).
+///
+/// The methods mirror those of `AtomicUsize`, so that the ordering arguments in `SharedPtr` hold
+/// for the atomic counter, and the non-atomic one simply ignores them.
+///
+/// # Safety
+///
+/// The counter must behave like an `AtomicUsize` used from a single thread, and have its layout, so
+/// that a unique `MyRc` can be turned into a `MyArc` in place. It is sealed for that reason.
+pub unsafe trait Count: private::Sealed {
+    fn new(n: usize) -> Self;
+    fn load(&self, order: atomic::Ordering) -> usize;
+    fn store(&self, n: usize, order: atomic::Ordering);
+    fn fetch_add(&self, n: usize, order: atomic::Ordering) -> usize;
+    fn fetch_sub(&self, n: usize, order: atomic::Ordering) -> usize;
+    fn compare_exchange(&self, current: usize, new: usize, success: atomic::Ordering, failure: atomic::Ordering) -> Result<usize, usize>;
+    fn compare_exchange_weak(&self, current: usize, new: usize, success: atomic::Ordering, failure: atomic::Ordering) -> Result<usize, usize>;
+    /// `atomic::fence`, if the counter needs it.
+    fn fence(order: atomic::Ordering);
+    /// The counter, if it can be read from any thread, for `track::live_arcs`.
+    #[cfg(feature = "track")]
+    #[doc(hidden)]
+    fn as_atomic(&self) -> Option<&AtomicUsize>;
+}
+
+unsafe impl Count for AtomicUsize {
+    fn new(n: usize) -> Self {
+        AtomicUsize::new(n)
+    }
+
+    fn load(&self, order: atomic::Ordering) -> usize {
+        AtomicUsize::load(self, order)
+    }
+
+    fn store(&self, n: usize, order: atomic::Ordering) {
+        AtomicUsize::store(self, n, order)
+    }
+
+    fn fetch_add(&self, n: usize, order: atomic::Ordering) -> usize {
+        AtomicUsize::fetch_add(self, n, order)
+    }
+
+    fn fetch_sub(&self, n: usize, order: atomic::Ordering) -> usize {
+        AtomicUsize::fetch_sub(self, n, order)
+    }
+
+    fn compare_exchange(&self, current: usize, new: usize, success: atomic::Ordering, failure: atomic::Ordering) -> Result<usize, usize> {
+        AtomicUsize::compare_exchange(self, current, new, success, failure)
+    }
+
+    fn compare_exchange_weak(&self, current: usize, new: usize, success: atomic::Ordering, failure: atomic::Ordering) -> Result<usize, usize> {
+        AtomicUsize::compare_exchange_weak(self, current, new, success, failure)
+    }
+
+    fn fence(order: atomic::Ordering) {
+        atomic::fence(order)
+    }
+
+    #[cfg(feature = "track")]
+    fn as_atomic(&self) -> Option<&AtomicUsize> {
+        Some(self)
+    }
+}
+
+// Only ever touched by one thread (MyRc is !Send and !Sync), so orderings are irrelevant.
+unsafe impl Count for Cell<usize> {
+    fn new(n: usize) -> Self {
+        Cell::new(n)
+    }
+
+    fn load(&self, _: atomic::Ordering) -> usize {
+        self.get()
+    }
+
+    fn store(&self, n: usize, _: atomic::Ordering) {
+        self.set(n)
+    }
+
+    fn fetch_add(&self, n: usize, _: atomic::Ordering) -> usize {
+        // Wrapping like AtomicUsize, the caller checks for overflow afterward.
+        self.replace(self.get().wrapping_add(n))
+    }
+
+    fn fetch_sub(&self, n: usize, _: atomic::Ordering) -> usize {
+        self.replace(self.get().wrapping_sub(n))
+    }
+
+    fn compare_exchange(&self, current: usize, new: usize, _: atomic::Ordering, _: atomic::Ordering) -> Result<usize, usize> {
+        let old = self.get();
+        if old == current {
+            self.set(new);
+            Ok(old)
+        } else {
+            Err(old)
+        }
+    }
+
+    fn compare_exchange_weak(&self, current: usize, new: usize, success: atomic::Ordering, failure: atomic::Ordering) -> Result<usize, usize> {
+        Count::compare_exchange(self, current, new, success, failure)
+    }
+
+    fn fence(_: atomic::Ordering) {}
+
+    #[cfg(feature = "track")]
+    fn as_atomic(&self) -> Option<&AtomicUsize> {
+        None
+    }
+}
+
+// Converting a MyRc to a MyArc in place relies on both counters having the same layout.
+#[cfg(not(loom))]
+const _: () = assert!(std::mem::size_of::<AtomicUsize>() == std::mem::size_of::<Cell<usize>>());
+#[cfg(not(loom))]
+const _: () = assert!(std::mem::align_of::<AtomicUsize>() == std::mem::align_of::<Cell<usize>>());
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for crate::sync::atomic::AtomicUsize {}
+    impl Sealed for std::cell::Cell<usize> {}
+}
@@ -0,0 +1,431 @@
+//! Epoch-based reclamation, to read `MyArc` values out of a shared structure without touching rc
+//! and to delay the drops of the unlinked ones until no reader is left.
+//!
+//! A thread `pin`s itself before reading, which records the global epoch it is in. A writer that
+//! unlinks a `MyArc` hands it to `Guard::defer_drop`, which puts it in the thread's garbage bag
+//! tagged with the current epoch. The global epoch only advances when every pinned thread has seen
+//! it, so once it moved twice past the tag no thread can still be reading the value, and the bag
+//! drops it. A `MyArc` handed to `defer_drop` is then dropped as usual, with its own fetch_sub,
+//! fence and free.
+//!
+//! `EpochArc` is the handle whose drop does this by itself. The drop that takes rc to 0 skips the
+//! fence and puts the allocation in the bag, and the bag is freed with a single fence for all of
+//! it. Any drop of an `EpochArc` is safe for the readers, there is no `defer_drop` to forget.
+//!
+//! There is a single collector for the whole process.
+
+use crate::{overflow, registry::{Entry, Registry}, retired::Retired, MyArc};
+use std::{cell::{Cell, UnsafeCell}, marker::PhantomData, mem::{self, ManuallyDrop}, ops::Deref, sync::{atomic::{self, AtomicPtr, AtomicUsize}, Mutex, PoisonError}};
+
+// A thread tries to advance the epoch and free its bag after this many pins.
+const PINS_BETWEEN_COLLECT: usize = 128;
+// Or when its bag holds this many values.
+const BAG_CAPACITY: usize = 64;
+
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+// A participant is never freed, an exiting thread leaves it for the next one to take.
+static PARTICIPANTS: Registry<Participant> = Registry::new();
+// Garbage of exited threads, freed by whichever thread collects next.
+static ORPHANS: Mutex<Vec<Deferred>> = Mutex::new(Vec::new());
+
+thread_local! {
+    static LOCAL: Local = Local::register();
+}
+
+struct Participant {
+    // epoch << 1 | 1 while pinned, 0 otherwise.
+    state: AtomicUsize,
+    // Only touched by the owning thread.
+    bag: UnsafeCell<Vec<Deferred>>,
+    guards: Cell<usize>,
+    pins: Cell<usize>
+}
+
+// Other threads only read state, the rest belongs to the thread that owns it.
+unsafe impl Sync for Participant {}
+
+// A MyArc waiting for the epoch to advance twice past `epoch`.
+struct Deferred {
+    epoch: usize,
+    arc: Retired
+}
+
+// The participant of the current thread.
+struct Local {
+    participant: &'static Entry<Participant>
+}
+
+impl Local {
+    fn register() -> Local {
+        let participant = PARTICIPANTS.acquire(|| Participant {
+            state: AtomicUsize::new(0),
+            bag: UnsafeCell::new(Vec::new()),
+            guards: Cell::new(0),
+            pins: Cell::new(0)
+        });
+        Local { participant }
+    }
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        let bag = mem::take(unsafe { &mut *self.participant.bag.get() });
+        // The fence of the `EpochArc` in the bag, which must be on the thread that took their rc to
+        // 0. The thread that frees them takes them from ORPHANS after us.
+        atomic::fence(atomic::Ordering::Acquire);
+        ORPHANS.lock().unwrap_or_else(PoisonError::into_inner).extend(bag);
+        self.participant.release();
+    }
+}
+
+/// Pins the current thread, values read through the guard stay valid until it is dropped.
+///
+/// Guards can be nested, the thread stays pinned until the last one is dropped.
+pub fn pin() -> Guard {
+    // A thread whose thread locals are already destroyed gets a participant for this guard only.
+    let (participant, local) = match LOCAL.try_with(|local| local.participant) {
+        Ok(participant) => (participant, None),
+        Err(_) => {
+            let local = Local::register();
+            (local.participant, Some(local))
+        }
+    };
+    let guards = participant.guards.get();
+    participant.guards.set(guards + 1);
+    if guards == 0 {
+        let epoch = EPOCH.load(atomic::Ordering::Relaxed);
+        participant.state.store(epoch << 1 | 1, atomic::Ordering::Relaxed);
+        // Our pinned state must be visible before we read anything from the shared structure,
+        // otherwise the epoch could advance twice and free what we are about to read.
+        atomic::fence(atomic::Ordering::SeqCst);
+
+        let pins = participant.pins.get() + 1;
+        participant.pins.set(pins);
+        if pins % PINS_BETWEEN_COLLECT == 0 {
+            collect(participant);
+        }
+    }
+    Guard { participant, _local: local, _marker: PhantomData }
+}
+
+/// Keeps the current thread pinned, returned by `pin`.
+pub struct Guard {
+    participant: &'static Entry<Participant>,
+    // Set if the participant is ours only, it is given back once we are unpinned.
+    _local: Option<Local>,
+    // Tied to the thread that pinned.
+    _marker: PhantomData<*mut ()>
+}
+
+impl Guard {
+    /// Loads `src`, the value can be used as long as the guard lives. Returns `None` if `src` is
+    /// null.
+    ///
+    /// # Safety
+    ///
+    /// `src` must only ever hold null or pointers from `MyArc::into_raw` or `EpochArc::into_raw`,
+    /// each owning one rc. A pointer removed from `src` must be turned back into a handle with the
+    /// matching `from_raw`, and a `MyArc` must be passed to `defer_drop`, never dropped directly.
+    pub unsafe fn load<'g, T>(&'g self, src: &AtomicPtr<T>) -> Option<&'g T> {
+        src.load(atomic::Ordering::Acquire).as_ref()
+    }
+
+    /// Drops `arc` once no thread pinned now can still be reading it.
+    pub fn defer_drop<T: Send + Sync + 'static>(&self, arc: MyArc<T>) {
+        self.defer(Retired::new(arc));
+    }
+
+    fn defer(&self, arc: Retired) {
+        // While we are pinned in epoch e the global one is e or e + 1 (it can't advance again until
+        // we see it), and reading it could give a stale e. Tagging with e + 1 is never too early.
+        let pinned = self.participant.state.load(atomic::Ordering::Relaxed) >> 1;
+        let deferred = Deferred { epoch: pinned + 1, arc };
+        let len = {
+            let bag = unsafe { &mut *self.participant.bag.get() };
+            bag.push(deferred);
+            bag.len()
+        };
+        if len >= BAG_CAPACITY {
+            collect(self.participant);
+        }
+    }
+
+    /// Tries to advance the epoch and drops what can be dropped, from this thread and exited ones.
+    pub fn flush(&self) {
+        collect(self.participant);
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let guards = self.participant.guards.get() - 1;
+        self.participant.guards.set(guards);
+        if guards == 0 {
+            // Release so that our reads happen before the epoch advances past us.
+            self.participant.state.store(0, atomic::Ordering::Release);
+        }
+    }
+}
+
+// Advances the epoch if every pinned thread is in the current one.
+fn try_advance() -> usize {
+    let epoch = EPOCH.load(atomic::Ordering::Relaxed);
+    atomic::fence(atomic::Ordering::SeqCst);
+
+    for participant in PARTICIPANTS.iter() {
+        let state = participant.state.load(atomic::Ordering::Relaxed);
+        if state & 1 == 1 && state >> 1 != epoch {
+            return epoch;
+        }
+    }
+    // Pairs with the Release of the unpinned threads, their reads happen before anything we free.
+    atomic::fence(atomic::Ordering::Acquire);
+
+    match EPOCH.compare_exchange(epoch, epoch + 1, atomic::Ordering::Release, atomic::Ordering::Relaxed) {
+        Ok(_) => epoch + 1,
+        Err(current) => current
+    }
+}
+
+fn collect(participant: &Participant) {
+    let epoch = try_advance();
+    // A value tagged e (no earlier than the epoch it was unlinked in) may still be read by threads
+    // pinned in e - 1 and e, so it is free once the epoch is e + 2.
+    let expired = |d: &Deferred| epoch >= d.epoch + 2;
+
+    // Take the values out before dropping them, a drop may defer others and push to the bag.
+    let mut free = Vec::new();
+    let bag = unsafe { &mut *participant.bag.get() };
+    let mut i = 0;
+    while i < bag.len() {
+        if expired(&bag[i]) {
+            free.push(bag.swap_remove(i));
+        } else {
+            i += 1;
+        }
+    }
+    if let Ok(mut orphans) = ORPHANS.try_lock() {
+        let (expired, kept): (Vec<_>, Vec<_>) = mem::take(&mut *orphans).into_iter().partition(expired);
+        *orphans = kept;
+        free.extend(expired);
+    }
+    if free.is_empty() {
+        return;
+    }
+    // The fence that the `EpochArc` in our bag skipped when their rc went to 0, one for all of them.
+    // Those from ORPHANS were covered by their thread before it left them there.
+    atomic::fence(atomic::Ordering::Acquire);
+    for deferred in free {
+        unsafe { deferred.arc.drop_arc() };
+    }
+}
+
+/// A `MyArc` for values read through `Guard::load`, whose last drop is deferred until no pinned
+/// thread can still be reading it, see src/epoch.rs.
+///
+/// There are no weak handles, and no way to turn it into a `MyArc`, whose drop would free the value
+/// right away.
+pub struct EpochArc<T: Send + Sync + 'static> {
+    arc: ManuallyDrop<MyArc<T>>
+}
+
+impl<T: Send + Sync + 'static> EpochArc<T> {
+    pub fn new(data: T) -> Self {
+        EpochArc { arc: ManuallyDrop::new(MyArc::new(data)) }
+    }
+
+    /// Same as `MyArc::into_raw`, the pointer can be stored for `Guard::load`.
+    pub fn into_raw(this: Self) -> *const T {
+        let this = ManuallyDrop::new(this);
+        MyArc::as_ptr(&this.arc)
+    }
+
+    /// Same as `MyArc::from_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `EpochArc::into_raw`, and the rc it owns is given back to the handle.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        EpochArc { arc: ManuallyDrop::new(MyArc::from_raw(ptr)) }
+    }
+
+    /// Same as `MyArc::strong_count`.
+    pub fn strong_count(this: &Self) -> usize {
+        MyArc::strong_count(&this.arc)
+    }
+}
+
+impl<T: Send + Sync + 'static> Deref for EpochArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.arc
+    }
+}
+
+impl<T: Send + Sync + 'static> Clone for EpochArc<T> {
+    fn clone(&self) -> Self {
+        EpochArc { arc: self.arc.clone() }
+    }
+}
+
+/// The first half of `Drop for MyArc`, the fence and the free are left to `collect`.
+impl<T: Send + Sync + 'static> Drop for EpochArc<T> {
+    fn drop(&mut self) {
+        let rc = &self.arc.inner().rc;
+        let old_rc = rc.fetch_sub(1, atomic::Ordering::Release);
+        if old_rc != 1 {
+            overflow::keep_saturated(rc, old_rc);
+            return;
+        }
+        let arc = unsafe { ManuallyDrop::take(&mut self.arc) };
+        pin().defer(Retired::released(arc));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{epoch::{self, EpochArc}, test_util::{Counted, DropCounts, DropFlag}, MyArc};
+    use std::{ptr, sync::atomic::{AtomicPtr, AtomicUsize, Ordering}};
+
+    // Other tests may be pinned for a moment, so the epoch can take a few tries to advance.
+    fn flush_until(done: impl Fn() -> bool) {
+        for _ in 0..1_000 {
+            epoch::pin().flush();
+            if done() {
+                return;
+            }
+            std::thread::yield_now();
+        }
+        panic!("deferred values were never dropped");
+    }
+
+    #[test]
+    fn test_epoch_defer() {
+        let a = MyArc::new(5);
+        let src = AtomicPtr::new(MyArc::into_raw(a.clone()) as *mut i32);
+        {
+            let guard = epoch::pin();
+            assert_eq!(unsafe { guard.load(&src) }, Some(&5));
+            let old = src.swap(ptr::null_mut(), Ordering::AcqRel);
+            guard.defer_drop(unsafe { MyArc::from_raw(old) });
+            // We are still pinned, so the epoch can't advance twice.
+            guard.flush();
+            guard.flush();
+            assert_eq!(MyArc::strong_count(&a), 2);
+            assert_eq!(unsafe { guard.load(&src) }, None);
+        }
+        flush_until(|| MyArc::strong_count(&a) == 1);
+    }
+
+    #[test]
+    fn test_epoch_stress() {
+        static COUNTS: DropCounts = DropCounts::new();
+        fn node(n: usize) -> *mut Counted {
+            MyArc::into_raw(MyArc::new(COUNTS.value(n))) as *mut Counted
+        }
+
+        let slots: Vec<_> = (0..4).map(|_| AtomicPtr::new(node(0))).collect();
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                let slots = &slots;
+                s.spawn(move || {
+                    for i in 0..5_000 {
+                        let guard = epoch::pin();
+                        let slot = &slots[(t + i) % slots.len()];
+                        let n = unsafe { guard.load(slot) }.unwrap().get();
+                        // Half of the threads only read, the others replace the value.
+                        if t % 2 == 0 {
+                            let old = slot.swap(node(n + 1), Ordering::AcqRel);
+                            guard.defer_drop(unsafe { MyArc::from_raw(old) });
+                        }
+                    }
+                });
+            }
+        });
+
+        for slot in &slots {
+            drop(unsafe { MyArc::from_raw(slot.load(Ordering::Relaxed)) });
+        }
+        flush_until(|| COUNTS.all_dropped());
+    }
+
+    #[test]
+    fn test_epoch_arc() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let a = EpochArc::new(DropFlag(&DROPS));
+        let src = AtomicPtr::new(EpochArc::into_raw(a.clone()) as *mut DropFlag);
+        {
+            let guard = epoch::pin();
+            assert!(unsafe { guard.load(&src) }.is_some());
+            drop(unsafe { EpochArc::from_raw(src.swap(ptr::null_mut(), Ordering::AcqRel)) });
+            assert_eq!(EpochArc::strong_count(&a), 1);
+            // The last handle, its rc goes to 0 but the value waits in the bag.
+            drop(a);
+            guard.flush();
+            guard.flush();
+            assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+        }
+        flush_until(|| DROPS.load(Ordering::Relaxed) == 1);
+    }
+
+    #[test]
+    fn test_epoch_arc_stress() {
+        static COUNTS: DropCounts = DropCounts::new();
+        fn node(n: usize) -> *mut Counted {
+            EpochArc::into_raw(EpochArc::new(COUNTS.value(n))) as *mut Counted
+        }
+
+        let slots: Vec<_> = (0..4).map(|_| AtomicPtr::new(node(0))).collect();
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                let slots = &slots;
+                s.spawn(move || {
+                    for i in 0..5_000 {
+                        let guard = epoch::pin();
+                        let slot = &slots[(t + i) % slots.len()];
+                        let n = unsafe { guard.load(slot) }.unwrap().get();
+                        if t % 2 == 0 {
+                            let old = slot.swap(node(n + 1), Ordering::AcqRel);
+                            // Dropped directly, sometimes with a clone alive on another thread.
+                            let old = unsafe { EpochArc::from_raw(old) };
+                            if i % 3 == 0 {
+                                let clone = old.clone();
+                                std::thread::spawn(move || drop(clone));
+                            }
+                            drop(old);
+                        }
+                    }
+                });
+            }
+        });
+
+        for slot in &slots {
+            drop(unsafe { EpochArc::from_raw(slot.load(Ordering::Relaxed)) });
+        }
+        flush_until(|| COUNTS.all_dropped());
+    }
+
+    #[test]
+    fn test_epoch_pin_in_thread_local_drop() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct DropLater(Option<EpochArc<DropFlag<'static>>>);
+        impl Drop for DropLater {
+            fn drop(&mut self) {
+                // LOCAL is already destroyed.
+                let _guard = epoch::pin();
+                self.0.take();
+            }
+        }
+        thread_local! {
+            static LATER: std::cell::RefCell<DropLater> = const { std::cell::RefCell::new(DropLater(None)) };
+        }
+
+        std::thread::spawn(|| {
+            // Thread locals are destroyed in the reverse order, so LOCAL goes first.
+            LATER.with(|later| later.borrow_mut().0 = Some(EpochArc::new(DropFlag(&DROPS))));
+            drop(epoch::pin());
+        }).join().unwrap();
+        flush_until(|| DROPS.load(Ordering::Relaxed) == 1);
+    }
+}
@@ -0,0 +1,348 @@
+//! Hazard pointers, to read a `MyArc` out of a lock-free data structure without touching its rc.
+//!
+//! The structure stores `MyArc::into_raw` pointers in `AtomicPtr`s. A reader publishes the pointer
+//! it is about to use in a hazard slot of the domain, then checks the `AtomicPtr` still holds it.
+//! A writer that unlinks a pointer doesn't drop its `MyArc` but hands it to `retire`, which keeps
+//! it on the retire list until no slot protects it. So the last `Drop for MyArc` of a protected
+//! pointer, and the free of its ArcInner, only happens once every reader has moved on.
+//!
+//! Each thread keeps a slot of every domain it protected pointers of, taken on its first `protect`
+//! and given back when the thread exits (or never, if the domain is dropped first). A guard created
+//! while the thread's slot is used by another guard takes a spare slot of the domain, for as long
+//! as it lives.
+
+use crate::{registry::{Entry, Registry}, retired::Retired, MyArc};
+use std::{cell::RefCell, mem, ops::Deref, ptr::{self, NonNull}, sync::{atomic::{self, AtomicBool, AtomicPtr}, Arc, Mutex, PoisonError, Weak}};
+
+// Scanning the slots is only worth it once a few pointers are waiting.
+const RECLAIM_THRESHOLD: usize = 64;
+
+/// The hazard slots and the retire list shared by a data structure.
+pub struct HazardDomain {
+    // Slots are never freed before the domain, a thread or guard that is done with one releases it
+    // so that another thread can take it. Shared with the threads that cache a slot, see `Cached`.
+    slots: Arc<Registry<HazardSlot>>,
+    // The MyArc removed from the data structure, waiting for their pointer not to be protected.
+    retired: Mutex<Vec<Retired>>
+}
+
+struct HazardSlot {
+    // The data pointer being read, null when the slot protects nothing.
+    protected: AtomicPtr<u8>,
+    // Whether a guard uses the slot that a thread caches. Only touched by that thread.
+    guarded: AtomicBool
+}
+
+thread_local! {
+    static CACHED: RefCell<Vec<Cached>> = const { RefCell::new(Vec::new()) };
+}
+
+// The slot the current thread owns in a domain.
+struct Cached {
+    // Doesn't keep the domain's slots alive, only lets us check they still are.
+    slots: Weak<Registry<HazardSlot>>,
+    slot: *const Entry<HazardSlot>
+}
+
+impl Drop for Cached {
+    fn drop(&mut self) {
+        // A dropped domain took the slot with it.
+        if let Some(_slots) = self.slots.upgrade() {
+            let slot = unsafe { &*self.slot };
+            // A guard in a thread local destroyed after us may still use it, then it stays taken.
+            if !slot.guarded.load(atomic::Ordering::Relaxed) {
+                slot.release();
+            }
+        }
+    }
+}
+
+// The slot of a guard, and whether it is the one the thread caches.
+struct GuardSlot<'a> {
+    slot: &'a Entry<HazardSlot>,
+    cached: bool
+}
+
+impl GuardSlot<'_> {
+    fn unprotect(&self) {
+        // Release so that our reads of the data happen before a reclaim that no longer sees us.
+        self.slot.protected.store(ptr::null_mut(), atomic::Ordering::Release);
+        if self.cached {
+            self.slot.guarded.store(false, atomic::Ordering::Relaxed);
+        } else {
+            self.slot.release();
+        }
+    }
+}
+
+impl HazardDomain {
+    pub fn new() -> Self {
+        HazardDomain {
+            slots: Arc::new(Registry::new()),
+            retired: Mutex::new(Vec::new())
+        }
+    }
+
+    /// Loads `src` and protects the pointer, so that it stays valid as long as the guard lives.
+    /// Returns `None` if `src` is null.
+    ///
+    /// # Safety
+    ///
+    /// `src` must only ever hold null or pointers from `MyArc::into_raw`, each owning one rc, and a
+    /// pointer removed from `src` must be turned back into a `MyArc` with `MyArc::from_raw` and
+    /// passed to `retire` on this domain, never dropped directly.
+    pub unsafe fn protect<T>(&self, src: &AtomicPtr<T>) -> Option<HazardGuard<'_, T>> {
+        self.protect_with(|order| src.load(order))
+    }
+
+    // `protect`, with the loads of src done by `load`.
+    pub(crate) unsafe fn protect_with<T>(&self, mut load: impl FnMut(atomic::Ordering) -> *mut T) -> Option<HazardGuard<'_, T>> {
+        let guard_slot = self.acquire_slot();
+        let slot = guard_slot.slot;
+        let mut ptr = load(atomic::Ordering::Relaxed);
+        loop {
+            if ptr.is_null() {
+                // A previous iteration may have published a pointer, which would stay protected
+                // until the slot is used again.
+                guard_slot.unprotect();
+                return None;
+            }
+            // SeqCst so that either `reclaim` sees our hazard, or we see that ptr was unlinked
+            // before it was retired and try again with the new value.
+            slot.protected.store(ptr as *mut u8, atomic::Ordering::SeqCst);
+            let current = load(atomic::Ordering::SeqCst);
+            if current == ptr {
+                return Some(HazardGuard { slot: guard_slot, ptr: NonNull::new_unchecked(ptr) });
+            }
+            ptr = current;
+        }
+    }
+
+    /// Drops `arc` once no guard of this domain protects it, which may be right away.
+    pub fn retire<T: Send + Sync + 'static>(&self, arc: MyArc<T>) {
+        let len = {
+            let mut retired = self.retired.lock().unwrap_or_else(PoisonError::into_inner);
+            retired.push(Retired::new(arc));
+            retired.len()
+        };
+        if len >= RECLAIM_THRESHOLD {
+            self.reclaim();
+        }
+    }
+
+    /// Drops the retired `MyArc` that aren't protected anymore.
+    pub fn reclaim(&self) {
+        // Take the list out, dropping a MyArc may retire others (e.g. the next node) and lock again.
+        let retired = mem::take(&mut *self.retired.lock().unwrap_or_else(PoisonError::into_inner));
+        if retired.is_empty() {
+            return;
+        }
+        // The retire, and so the unlink before it, happened before we took the list, but the unlink
+        // needn't be SeqCst. The fence orders it before the loads of the slots below, against the
+        // SeqCst store and load in `protect`: a reader whose hazard we miss sees the unlink.
+        atomic::fence(atomic::Ordering::SeqCst);
+
+        let hazards: Vec<_> = self.slots.iter()
+            .map(|slot| slot.protected.load(atomic::Ordering::SeqCst) as *const u8)
+            .filter(|ptr| !ptr.is_null())
+            .collect();
+
+        let (protected, free): (Vec<_>, Vec<_>) = retired.into_iter().partition(|r| hazards.contains(&r.as_ptr()));
+        self.retired.lock().unwrap_or_else(PoisonError::into_inner).extend(protected);
+        for r in free {
+            unsafe { r.drop_arc() };
+        }
+    }
+
+    // The slot the current thread caches for this domain, or a spare one if a guard uses it.
+    fn acquire_slot(&self) -> GuardSlot<'_> {
+        let new_slot = || HazardSlot { protected: AtomicPtr::new(ptr::null_mut()), guarded: AtomicBool::new(false) };
+        // A thread whose thread locals are already destroyed caches nothing.
+        let cached = CACHED.try_with(|cached| {
+            let mut cached = cached.borrow_mut();
+            if let Some(c) = cached.iter().find(|c| Weak::as_ptr(&c.slots) == Arc::as_ptr(&self.slots)) {
+                return c.slot;
+            }
+            // Forget the slots of the domains that were dropped since.
+            cached.retain(|c| c.slots.strong_count() > 0);
+            let slot = self.slots.acquire(new_slot);
+            cached.push(Cached { slots: Arc::downgrade(&self.slots), slot });
+            slot as *const Entry<HazardSlot>
+        });
+        if let Ok(slot) = cached {
+            // The slot is ours as long as the domain lives, which is longer than the guard.
+            let slot = unsafe { &*slot };
+            if !slot.guarded.swap(true, atomic::Ordering::Relaxed) {
+                return GuardSlot { slot, cached: true };
+            }
+        }
+        GuardSlot { slot: self.slots.acquire(new_slot), cached: false }
+    }
+}
+
+impl Default for HazardDomain {
+    fn default() -> Self {
+        HazardDomain::new()
+    }
+}
+
+impl Drop for HazardDomain {
+    fn drop(&mut self) {
+        // Guards borrow the domain, so nothing is protected anymore.
+        let retired = mem::take(self.retired.get_mut().unwrap_or_else(PoisonError::into_inner));
+        for r in retired {
+            unsafe { r.drop_arc() };
+        }
+    }
+}
+
+/// A pointer protected by a hazard slot, returned by `HazardDomain::protect`.
+pub struct HazardGuard<'a, T> {
+    slot: GuardSlot<'a>,
+    ptr: NonNull<T>
+}
+
+impl<T> HazardGuard<'_, T> {
+    /// Returns a new `MyArc` to the protected data, which outlives the guard.
+    pub fn to_arc(this: &Self) -> MyArc<T> {
+        unsafe {
+            // The MyArc is either still linked or on the retire list, so rc is at least 1.
+            MyArc::increment_strong_count(this.ptr.as_ptr());
+            MyArc::from_raw(this.ptr.as_ptr())
+        }
+    }
+
+    pub fn as_ptr(this: &Self) -> *const T {
+        this.ptr.as_ptr()
+    }
+}
+
+impl<T> Deref for HazardGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> Drop for HazardGuard<'_, T> {
+    fn drop(&mut self) {
+        self.slot.unprotect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::{Counted, DropCounts}, HazardDomain, HazardGuard, MyArc};
+    use std::{ptr, sync::atomic::{AtomicPtr, Ordering}};
+
+    #[test]
+    fn test_hazard_protect() {
+        let domain = HazardDomain::new();
+        let a = MyArc::new(String::from("node"));
+        let src = AtomicPtr::new(MyArc::into_raw(a.clone()) as *mut String);
+
+        let guard = unsafe { domain.protect(&src) }.unwrap();
+        assert_eq!(*guard, "node");
+        let b = HazardGuard::to_arc(&guard);
+        assert_eq!(MyArc::strong_count(&a), 3);
+        drop(b);
+
+        // Unlink and retire while protected, the slot's MyArc stays alive.
+        let old = src.swap(ptr::null_mut(), Ordering::SeqCst);
+        domain.retire(unsafe { MyArc::from_raw(old) });
+        domain.reclaim();
+        assert_eq!(MyArc::strong_count(&a), 2);
+        assert!(unsafe { domain.protect(&src) }.is_none());
+
+        drop(guard);
+        domain.reclaim();
+        assert_eq!(MyArc::strong_count(&a), 1);
+
+        // src is unlinked between publishing the pointer and checking it, and protect returns None.
+        let raw = MyArc::into_raw(a.clone()) as *mut String;
+        let mut loads = IntoIterator::into_iter([raw, ptr::null_mut()]);
+        assert!(unsafe { domain.protect_with(|_| loads.next().unwrap()) }.is_none());
+        domain.retire(unsafe { MyArc::from_raw(raw) });
+        domain.reclaim();
+        assert_eq!(MyArc::strong_count(&a), 1);
+    }
+
+    #[test]
+    fn test_hazard_threads() {
+        // Same as test_atomic_my_arc_threads, with readers that never touch rc.
+        static COUNTS: DropCounts = DropCounts::new();
+        let domain = HazardDomain::new();
+        let src = AtomicPtr::new(MyArc::into_raw(MyArc::new(COUNTS.value(0))) as *mut Counted);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut last = 0;
+                    for _ in 0..10_000 {
+                        let n = unsafe { domain.protect(&src) }.unwrap().get();
+                        assert!(n >= last);
+                        last = n;
+                    }
+                });
+            }
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..1_000 {
+                        let current = unsafe { domain.protect(&src) }.unwrap();
+                        let next = MyArc::into_raw(MyArc::new(COUNTS.value(current.get() + 1))) as *mut Counted;
+                        let current = HazardGuard::as_ptr(&current) as *mut Counted;
+                        match src.compare_exchange(current, next, Ordering::SeqCst, Ordering::Relaxed) {
+                            Ok(old) => domain.retire(unsafe { MyArc::from_raw(old) }),
+                            Err(_) => drop(unsafe { MyArc::from_raw(next) })
+                        }
+                    }
+                });
+            }
+        });
+
+        domain.retire(unsafe { MyArc::from_raw(src.load(Ordering::Relaxed)) });
+        drop(domain);
+        assert!(COUNTS.all_dropped());
+    }
+
+    #[test]
+    fn test_hazard_slot_per_thread() {
+        let domain = HazardDomain::new();
+        let a = MyArc::new(1);
+        let src = AtomicPtr::new(MyArc::into_raw(a.clone()) as *mut i32);
+
+        // Guards one after the other use the slot of the thread.
+        for _ in 0..10 {
+            drop(unsafe { domain.protect(&src) }.unwrap());
+        }
+        assert_eq!(domain.slots.iter().count(), 1);
+        // A nested guard takes a spare slot, and gives it back.
+        let outer = unsafe { domain.protect(&src) }.unwrap();
+        let inner = unsafe { domain.protect(&src) }.unwrap();
+        assert_eq!(domain.slots.iter().count(), 2);
+        drop((inner, outer));
+        drop(unsafe { domain.protect(&src) }.unwrap());
+        assert_eq!(domain.slots.iter().count(), 2);
+
+        // Each thread takes the spare slot, and gives it back when it exits.
+        for _ in 0..3 {
+            std::thread::scope(|s| {
+                s.spawn(|| drop(unsafe { domain.protect(&src) }.unwrap()));
+            });
+        }
+        assert_eq!(domain.slots.iter().count(), 2);
+
+        // The thread outlives a domain it protected pointers of.
+        std::thread::spawn(|| {
+            let domain = HazardDomain::new();
+            let src = AtomicPtr::new(MyArc::into_raw(MyArc::new(2)) as *mut i32);
+            assert_eq!(*unsafe { domain.protect(&src) }.unwrap(), 2);
+            domain.retire(unsafe { MyArc::from_raw(src.load(Ordering::Relaxed)) });
+            drop(domain);
+            let domain = HazardDomain::new();
+            assert!(unsafe { domain.protect(&AtomicPtr::<i32>::new(ptr::null_mut())) }.is_none());
+        }).join().unwrap();
+
+        drop(unsafe { MyArc::from_raw(src.load(Ordering::Relaxed)) });
+    }
+}
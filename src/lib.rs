@@ -1,46 +1,839 @@
-use std::{marker::PhantomData, ops::{Deref, DerefMut}, ptr::NonNull, sync::atomic};
+mod allocator;
+// The types below with their own threads, thread locals and std atomics are left out of loom builds,
+// the model tests only cover the counts of MyArc.
+#[cfg(not(loom))]
+mod atomic_arc;
+#[cfg(not(loom))]
+mod biased;
+mod borrow;
+mod by_address;
+mod count;
+#[cfg(not(loom))]
+pub mod epoch;
+#[cfg(not(loom))]
+mod hazard;
+mod mapped;
+mod overflow;
+#[cfg(not(loom))]
+mod retired;
+#[cfg(not(loom))]
+mod registry;
+#[cfg(not(loom))]
+mod sharded;
+mod sync;
+#[cfg(all(test, not(loom)))]
+mod test_util;
+mod thin;
+#[cfg(feature = "track")]
+pub mod track;
+mod unique;
 
-pub struct MyArc<T> {
+pub use allocator::{AllocError, Allocator, Global};
+#[cfg(not(loom))]
+pub use atomic_arc::{AtomicMyArc, AtomicOptionMyArc};
+#[cfg(not(loom))]
+pub use biased::BiasedArc;
+pub use borrow::MyArcBorrow;
+pub use by_address::ByAddress;
+pub use count::Count;
+#[cfg(not(loom))]
+pub use hazard::{HazardDomain, HazardGuard};
+pub use mapped::MappedArc;
+#[cfg(not(loom))]
+pub use sharded::ShardedArc;
+pub use thin::{HeaderSlice, ThinArc, ThinArcBorrow};
+pub use unique::UniqueArc;
+
+use std::{alloc::{self, Layout}, borrow::Borrow, cell::Cell, cmp::Ordering, error::Error, fmt, hash::{Hash, Hasher}, io, iter::FromIterator, marker::PhantomData, mem::{self, ManuallyDrop, MaybeUninit}, ops::Deref, pin::Pin, ptr::{self, NonNull}};
+use sync::{atomic::{self, AtomicUsize}, hint};
+
+// Counts above this are treated as overflow, see `Clone for MyArc` and src/overflow.rs.
+const MAX_REFCOUNT: usize = isize::MAX as usize;
+
+/// A thread-safe reference-counted pointer, the counts are updated with atomic operations.
+///
+/// A `MyArc<File>` (or `TcpStream`, `UnixStream`) reads and writes like `Arc<File>`, all the
+/// handles share the position of the file. A `MyArc<[u8]>` is not a reader, each read would start
+/// over from the first byte:
+///
+/// ```compile_fail
+/// fn assert_read<R: std::io::Read>() {}
+/// assert_read::<my_arc::MyArc<[u8]>>();
+/// ```
+///
+/// Read it through an `io::Cursor` instead, which keeps the position:
+///
+/// ```
+/// use std::io::Read;
+/// let mut cursor = std::io::Cursor::new(my_arc::MyArc::<[u8]>::from(vec![1, 2, 3]));
+/// let mut buf = [0; 2];
+/// assert_eq!(cursor.read(&mut buf).unwrap(), 2);
+/// assert_eq!(cursor.read(&mut buf).unwrap(), 1);
+/// assert_eq!(buf[0], 3);
+/// ```
+pub type MyArc<T, A = Global> = SharedPtr<T, AtomicUsize, A>;
+
+/// The non-owning handle of a `MyArc`, see `WeakPtr`.
+pub type MyWeak<T, A = Global> = WeakPtr<T, AtomicUsize, A>;
+
+/// The single-threaded version of `MyArc`, which doesn't pay for atomic operations. It can't be sent
+/// or shared across threads:
+///
+/// ```compile_fail
+/// fn assert_send<T: Send>() {}
+/// assert_send::<my_arc::MyRc<i32>>();
+/// ```
+pub type MyRc<T, A = Global> = SharedPtr<T, Cell<usize>, A>;
+
+/// The non-owning handle of a `MyRc`, see `WeakPtr`.
+pub type MyRcWeak<T, A = Global> = WeakPtr<T, Cell<usize>, A>;
+
+/// The reference-counted pointer behind `MyArc` and `MyRc`, `C` is how the counts are kept.
+pub struct SharedPtr<T: ?Sized, C: Count, A: Allocator = Global> {
     // ptr is variant of T
-    ptr: NonNull<ArcInner<T>>,
+    ptr: NonNull<ArcInner<T, C>>,
     // as if we own the data T
-    _marker: PhantomData<T>
+    _marker: PhantomData<T>,
+    // The allocator ArcInner comes from, and where it goes back when the last handle is dropped.
+    alloc: A
+}
+
+/// A non-owning handle to the data of a `MyArc` (or `MyRc`).
+///
+/// It keeps the allocation alive but not the data: once the last `MyArc` is gone the data is
+/// dropped and `upgrade` returns `None`.
+pub struct WeakPtr<T: ?Sized, C: Count, A: Allocator = Global> {
+    // usize::MAX if the handle was created by `MyWeak::new`, in which case nothing is allocated.
+    ptr: NonNull<ArcInner<T, C>>,
+    alloc: A
 }
 
-pub struct ArcInner<T> {
+// repr(C) so that the header comes first and the offset of data only depends on its alignment,
+// which is how we lay out unsized values by hand in `allocate_for_layout`.
+#[repr(C)]
+pub struct ArcInner<T: ?Sized, C = AtomicUsize> {
     // Rc is used to record the last owner of this data, which could be used cross-thread.
-    rc: atomic::AtomicUsize,
+    rc: C,
+    // Number of MyWeak pointing to this allocation, plus one shared by all the MyArc as long as rc
+    // is not 0. The data is dropped when rc reaches 0, the allocation is freed when weak does.
+    weak: C,
     data: T
 }
 
 // Bounds <T: Send + Sync> is requied as we don't want data races.
 // e.g. MyArc<Rc<String>>, Rc is not thread-safe( T: !(Send+Sync)). If the bound is not present, Rc
 // will be shared across threads where data race happens.
-unsafe impl<T: Send + Sync> Send for MyArc<T> {}
-unsafe impl<T: Send + Sync> Sync for MyArc<T> {}
+// The allocator is moved along with the handle (Send), and cloned from a shared one (Sync).
+// Only for atomic counts, a MyRc is neither Send nor Sync since NonNull isn't.
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Send> Send for MyArc<T, A> {}
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Sync> Sync for MyArc<T, A> {}
+
+// A MyWeak can be upgraded to a MyArc on another thread, so it needs the same bounds.
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Send> Send for MyWeak<T, A> {}
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Sync> Sync for MyWeak<T, A> {}
 
-impl<T> MyArc<T> {
+// Moving a handle never moves the data, which stays in the allocation, so a MyArc is Unpin even if
+// T isn't (unlike PhantomData<T>, which would make it depend on T).
+impl<T: ?Sized, C: Count, A: Allocator> Unpin for SharedPtr<T, C, A> {}
+
+impl<T, C: Count> SharedPtr<T, C> {
+    #[cfg_attr(feature = "track", track_caller)]
     pub fn new(data: T) -> Self {
-        let inner = ArcInner {
-            rc: atomic::AtomicUsize::new(1),
-            data
+        SharedPtr::new_in(data, Global)
+    }
+
+    /// Creates a pinned `MyArc`, whose data is never moved until it is dropped.
+    ///
+    /// No handle gives out `&mut T` while others may exist, and a `Pin<MyArc<T>>` can't even be
+    /// turned back into a `MyArc` unless `T: Unpin`, so the data can't be moved out of it:
+    ///
+    /// ```compile_fail
+    /// let mut p = my_arc::MyArc::pin(std::marker::PhantomPinned);
+    /// let _ = p.as_mut();
+    /// ```
+    #[cfg_attr(feature = "track", track_caller)]
+    pub fn pin(data: T) -> Pin<Self> {
+        // A new MyArc is the only handle, see `try_into_pin`.
+        unsafe { Pin::new_unchecked(SharedPtr::new(data)) }
+    }
+
+    /// Creates a `MyArc` whose data can hold a `MyWeak` to itself.
+    ///
+    /// `data_fn` gets a `MyWeak` to the allocation before the data exists, so upgrading it (or any
+    /// clone of it) returns `None` until `new_cyclic` returns.
+    #[cfg_attr(feature = "track", track_caller)]
+    pub fn new_cyclic<F: FnOnce(&WeakPtr<T, C>) -> T>(data_fn: F) -> Self {
+        // rc starts at 0 so that upgrades fail while the data is uninitialized. The weak count is
+        // for the MyWeak passed to data_fn, which becomes the one held by all the MyArc afterward.
+        let uninit = ArcInner {
+            rc: C::new(0),
+            weak: C::new(1),
+            data: MaybeUninit::<T>::uninit()
         };
+        // ArcInner is repr(C) and MaybeUninit<T> has the layout of T.
+        let ptr = NonNull::new(Box::into_raw(Box::new(uninit))).unwrap().cast::<ArcInner<T, C>>();
+        #[cfg(feature = "track")]
+        unsafe { track::register(ptr.as_ptr()) };
+
+        // If data_fn panics, dropping weak frees the memory without touching the data.
+        let weak = WeakPtr { ptr, alloc: Global };
+        let data = data_fn(&weak);
+
+        unsafe {
+            let inner = ptr.as_ptr();
+            ptr::write(ptr::addr_of_mut!((*inner).data), data);
+            // Publish the data, Release pairs with the Acquire in `MyWeak::upgrade` so that clones
+            // of weak upgraded on other threads see it initialized.
+            (*inner).rc.store(1, atomic::Ordering::Release);
+        }
+        mem::forget(weak);
+
+        unsafe { SharedPtr::from_inner(ptr.as_ptr()) }
+    }
+
+    /// Creates a `MyArc` with uninitialized data, to be written in place, e.g. with `get_mut`, and
+    /// then turned into a `MyArc<T>` with `assume_init`.
+    ///
+    /// Unlike `new`, the data is never built on the stack and moved into the allocation, so this
+    /// works for values too large for the stack.
+    #[cfg_attr(feature = "track", track_caller)]
+    pub fn new_uninit() -> SharedPtr<MaybeUninit<T>, C> {
+        unsafe {
+            SharedPtr::from_inner(SharedPtr::allocate_for_layout(
+                Layout::new::<T>(),
+                |layout| Global.allocate(layout),
+                |mem| mem as *mut ArcInner<MaybeUninit<T>, C>
+            ))
+        }
+    }
+
+    /// Same as `new`, but returns an error instead of aborting if the memory can't be allocated.
+    #[cfg_attr(feature = "track", track_caller)]
+    pub fn try_new(data: T) -> Result<Self, AllocError> {
+        SharedPtr::try_new_in(data, Global)
+    }
+
+    /// Same as `new_uninit`, but returns an error instead of aborting if the memory can't be
+    /// allocated.
+    #[cfg_attr(feature = "track", track_caller)]
+    pub fn try_new_uninit() -> Result<SharedPtr<MaybeUninit<T>, C>, AllocError> {
+        SharedPtr::try_new_uninit_in(Global)
+    }
+
+    /// Same as `new_uninit`, with the data filled with zero bytes.
+    #[cfg_attr(feature = "track", track_caller)]
+    pub fn new_zeroed() -> SharedPtr<MaybeUninit<T>, C> {
+        unsafe {
+            SharedPtr::from_inner(SharedPtr::allocate_for_layout(
+                Layout::new::<T>(),
+                |layout| Global.allocate_zeroed(layout),
+                |mem| mem as *mut ArcInner<MaybeUninit<T>, C>
+            ))
+        }
+    }
+}
+
+impl<T, C: Count, A: Allocator> SharedPtr<T, C, A> {
+    /// Same as `new`, with the memory coming from `alloc`.
+    #[cfg_attr(feature = "track", track_caller)]
+    pub fn new_in(data: T, alloc: A) -> Self {
+        let layout = arcinner_layout_for_value_layout(Layout::new::<T>());
+        match SharedPtr::try_new_in(data, alloc) {
+            Ok(this) => this,
+            Err(_) => alloc::handle_alloc_error(layout),
+        }
+    }
+
+    /// Same as `try_new`, with the memory coming from `alloc`.
+    #[cfg_attr(feature = "track", track_caller)]
+    pub fn try_new_in(data: T, alloc: A) -> Result<Self, AllocError> {
+        let uninit = SharedPtr::try_new_uninit_in(alloc)?;
+        unsafe {
+            ptr::write(SharedPtr::as_ptr(&uninit) as *mut T, data);
+            Ok(uninit.assume_init())
+        }
+    }
+
+    /// Same as `try_new_uninit`, with the memory coming from `alloc`.
+    #[cfg_attr(feature = "track", track_caller)]
+    pub fn try_new_uninit_in(alloc: A) -> Result<SharedPtr<MaybeUninit<T>, C, A>, AllocError> {
+        unsafe {
+            let inner = SharedPtr::try_allocate_for_layout(
+                Layout::new::<T>(),
+                |layout| alloc.allocate(layout),
+                |mem| mem as *mut ArcInner<MaybeUninit<T>, C>
+            )?;
+            Ok(SharedPtr::from_inner_in(inner, alloc))
+        }
+    }
+
+    /// Returns the data if this is the only `MyArc`, otherwise gives the handle back.
+    ///
+    /// Note that two owners racing with `try_unwrap` can both fail, use `into_inner` if one of them
+    /// must get the data.
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        // Relaxed is enough for the compare_exchange as the fence below does the synchronization,
+        // exactly as the fetch_sub and fence in `Drop for MyArc`.
+        if this.inner().rc.compare_exchange(1, 0, atomic::Ordering::Relaxed, atomic::Ordering::Relaxed).is_err() {
+            return Err(this);
+        }
+        C::fence(atomic::Ordering::Acquire);
+
+        let this = ManuallyDrop::new(this);
+        unsafe {
+            let data = ptr::read(&this.inner().data);
+            // Release the weak reference held by all MyArc, the data is moved so it isn't dropped.
+            drop(WeakPtr { ptr: this.ptr, alloc: ptr::read(&this.alloc) });
+            Ok(data)
+        }
+    }
+
+    /// Drops this handle and returns the data if it was the last `MyArc`.
+    ///
+    /// If every owner calls `into_inner`, exactly one of them gets the data, which is not the case
+    /// with `try_unwrap`.
+    pub fn into_inner(this: Self) -> Option<T> {
+        // Same as `Drop for MyArc`, except that the data is moved out instead of dropped.
+        let this = ManuallyDrop::new(this);
+        let alloc = unsafe { ptr::read(&this.alloc) };
+        let old_rc = this.inner().rc.fetch_sub(1, atomic::Ordering::Release);
+        if old_rc != 1 {
+            overflow::keep_saturated(&this.inner().rc, old_rc);
+            return None;
+        }
+        C::fence(atomic::Ordering::Acquire);
 
-        MyArc {
-            ptr: NonNull::new(Box::into_raw(Box::new(inner))).unwrap(),
-            _marker: PhantomData
+        unsafe {
+            let data = ptr::read(&this.inner().data);
+            drop(WeakPtr { ptr: this.ptr, alloc });
+            Some(data)
         }
     }
+}
+
+impl<T: Clone, C: Count, A: Allocator> SharedPtr<T, C, A> {
+    /// Returns the data if this is the only `MyArc`, otherwise a clone of it.
+    pub fn unwrap_or_clone(this: Self) -> T {
+        SharedPtr::try_unwrap(this).unwrap_or_else(|this| (*this).clone())
+    }
+}
+
+impl<T: ?Sized, C: Count> SharedPtr<T, C> {
+    unsafe fn from_inner(ptr: *mut ArcInner<T, C>) -> Self {
+        SharedPtr::from_inner_in(ptr, Global)
+    }
+
+    /// Takes back the ownership of a pointer returned by `into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `MyArc::<T>::into_raw` (with the same `T`, or one it was unsized into)
+    /// and every `from_raw` must be matched by an `into_raw` or an `increment_strong_count`,
+    /// otherwise rc goes out of sync and the data is freed while still in use.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        SharedPtr::from_raw_in(ptr, Global)
+    }
+
+    /// Increases rc of the `MyArc` behind a pointer returned by `into_raw`, as if it was cloned.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw` and rc must be at least 1 for the whole call.
+    pub unsafe fn increment_strong_count(ptr: *const T) {
+        // Borrow the MyArc without dropping it, and forget the clone so the increment stays.
+        let arc = ManuallyDrop::new(SharedPtr::from_raw(ptr));
+        let _arc_clone: ManuallyDrop<SharedPtr<T, C>> = arc.clone();
+    }
 
+    /// Decreases rc of the `MyArc` behind a pointer returned by `into_raw`, as if it was dropped,
+    /// which drops the data if that was the last one.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw` and rc must be at least 1 when calling it.
+    pub unsafe fn decrement_strong_count(ptr: *const T) {
+        drop(SharedPtr::<T, C>::from_raw(ptr));
+    }
+
+    /// Allocates an ArcInner with room for a value of `value_layout` using `allocate`, with both
+    /// counts set to 1 and the data left as `allocate` returned it. `mem_to_arcinner` turns the
+    /// address of the block into a pointer to ArcInner, which is where the length or vtable of an
+    /// unsized T is attached.
+    #[cfg_attr(feature = "track", track_caller)]
+    unsafe fn allocate_for_layout(
+        value_layout: Layout,
+        allocate: impl FnOnce(Layout) -> Result<NonNull<u8>, AllocError>,
+        mem_to_arcinner: impl FnOnce(*mut u8) -> *mut ArcInner<T, C>
+    ) -> *mut ArcInner<T, C> {
+        let layout = arcinner_layout_for_value_layout(value_layout);
+        Self::try_allocate_for_layout(value_layout, allocate, mem_to_arcinner)
+            .unwrap_or_else(|_| alloc::handle_alloc_error(layout))
+    }
+
+    /// Same as `allocate_for_layout`, but returns an error instead of aborting if `allocate` fails.
+    #[cfg_attr(feature = "track", track_caller)]
+    unsafe fn try_allocate_for_layout(
+        value_layout: Layout,
+        allocate: impl FnOnce(Layout) -> Result<NonNull<u8>, AllocError>,
+        mem_to_arcinner: impl FnOnce(*mut u8) -> *mut ArcInner<T, C>
+    ) -> Result<*mut ArcInner<T, C>, AllocError> {
+        let layout = arcinner_layout_for_value_layout(value_layout);
+        let mem = allocate(layout)?;
+
+        let inner = mem_to_arcinner(mem.as_ptr());
+        ptr::write(ptr::addr_of_mut!((*inner).rc), C::new(1));
+        ptr::write(ptr::addr_of_mut!((*inner).weak), C::new(1));
+        #[cfg(feature = "track")]
+        track::register(inner);
+        Ok(inner)
+    }
+}
+
+impl<T: ?Sized, C: Count, A: Allocator> SharedPtr<T, C, A> {
+    /// Returns the number of `MyArc` pointing to the data, read with Acquire ordering.
+    ///
+    /// Kept as it was for existing callers. The value is stale as soon as it is read, so deciding
+    /// from it whether the data is shared is a race: use `is_unique` or `get_mut` for that, and
+    /// `strong_count` (a Relaxed load) for a hint.
+    #[deprecated(note = "use strong_count / is_unique")]
     pub fn count(&self) -> usize {
-        let inner = self.ptr.as_ptr();
+        self.inner().rc.load(atomic::Ordering::Acquire)
+    }
+
+    /// Returns the number of `MyArc` pointing to the data.
+    ///
+    /// Other threads may clone and drop handles right after it is read, so it is only a hint, and
+    /// it doesn't synchronize with them: use `is_unique` or `get_mut` to decide whether the data can
+    /// be mutated.
+    pub fn strong_count(this: &Self) -> usize {
+        this.inner().rc.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the number of `MyWeak` pointing to the data, with the same caveats as
+    /// `strong_count`.
+    pub fn weak_count(this: &Self) -> usize {
+        match this.inner().weak.load(atomic::Ordering::Relaxed) {
+            // Locked by `is_unique`, which only succeeds when there is no MyWeak.
+            usize::MAX => 0,
+            // All the MyArc together hold one weak.
+            weak => weak - 1
+        }
+    }
+
+    /// Returns whether both `MyArc` point to the same allocation, like `ptr::eq` on `as_ptr` but
+    /// ignoring the length or vtable.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.ptr.as_ptr() as *const u8 == other.ptr.as_ptr() as *const u8
+    }
+
+    /// Same as `==`, but returns `true` right away when both point to the same allocation, which
+    /// `Eq` allows, as `Arc` does. `==` itself always compares the data, see `PartialEq`.
+    pub fn eq_fast(this: &Self, other: &Self) -> bool
+    where
+        T: Eq
+    {
+        SharedPtr::ptr_eq(this, other) || **this == **other
+    }
+
+    /// Same as `clone`, but returns `None` instead of applying the overflow policy if rc is
+    /// already at its maximum (or saturated).
+    pub fn try_clone(&self) -> Option<Self>
+    where
+        A: Clone
+    {
+        let inner = self.inner();
+        let mut n = inner.rc.load(atomic::Ordering::Relaxed);
+        loop {
+            if n >= MAX_REFCOUNT {
+                return None;
+            }
+            // Relaxed for the same reason as in `Clone for MyArc`.
+            match inner.rc.compare_exchange_weak(n, n + 1, atomic::Ordering::Relaxed, atomic::Ordering::Relaxed) {
+                Ok(_) => return Some(unsafe { SharedPtr::from_inner_in(self.ptr.as_ptr(), self.alloc.clone()) }),
+                Err(old) => n = old,
+            }
+        }
+    }
+
+    /// Creates a `MyWeak` pointing to the same allocation.
+    pub fn downgrade(this: &Self) -> WeakPtr<T, C, A>
+    where
+        A: Clone
+    {
+        let inner = this.inner();
+        let mut cur = inner.weak.load(atomic::Ordering::Relaxed);
+        loop {
+            // weak is usize::MAX while `is_unique` is checking rc, wait until it is released.
+            if cur == usize::MAX {
+                hint::spin_loop();
+                cur = inner.weak.load(atomic::Ordering::Relaxed);
+                continue;
+            }
+            // Same threshold as `Clone for MyArc`. A saturated weak count isn't incremented.
+            if cur >= MAX_REFCOUNT {
+                overflow::on_overflow_no_increment(&inner.weak);
+                return WeakPtr { ptr: this.ptr, alloc: this.alloc.clone() };
+            }
+            // Acquire synchronizes with the Release store in `is_unique`, so a MyWeak can't be
+            // created in the middle of a check that concluded the handle was unique.
+            match inner.weak.compare_exchange_weak(cur, cur + 1, atomic::Ordering::Acquire, atomic::Ordering::Relaxed) {
+                Ok(_) => return WeakPtr { ptr: this.ptr, alloc: this.alloc.clone() },
+                Err(old) => cur = old,
+            }
+        }
+    }
+
+    /// Returns a mutable reference to the data if there is no other `MyArc` or `MyWeak` pointing
+    /// to it, as anyone else could be reading it at the same time.
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        if SharedPtr::is_unique(this) {
+            // We are the only handle, and `&mut self` makes sure we can't be cloned meanwhile.
+            unsafe { Some(&mut (*this.ptr.as_ptr()).data) }
+        } else {
+            None
+        }
+    }
+
+    /// Pins the data if this is the only `MyArc` and there is no `MyWeak`, see `pin`, otherwise
+    /// gives the handle back.
+    ///
+    /// Another handle would stay unpinned, and could move the data out with e.g. `try_unwrap` once
+    /// the pinned ones are dropped.
+    pub fn try_into_pin(mut this: Self) -> Result<Pin<Self>, Self> {
+        if !SharedPtr::is_unique(&mut this) {
+            return Err(this);
+        }
+        // The data stays where it is until the last handle drops it, and pinned handles have no way
+        // to move it: get_mut, make_mut and try_unwrap need an unpinned MyArc, and a MyWeak can't be
+        // created from a Pin<MyArc>.
+        unsafe { Ok(Pin::new_unchecked(this)) }
+    }
+
+    /// Returns the allocator the data was allocated with.
+    pub fn allocator(this: &Self) -> &A {
+        &this.alloc
+    }
+
+    /// Returns whether this is the only `MyArc` or `MyWeak` pointing to the data.
+    ///
+    /// When it returns `true`, everything the other handles did with the data happens before what
+    /// the caller does next, so it may mutate it (as `get_mut` does), and `&mut` makes sure no handle
+    /// can be created meanwhile.
+    pub fn is_unique(this: &mut Self) -> bool {
+        // Lock weak by setting it to usize::MAX so that nobody can downgrade while we check rc,
+        // otherwise a MyWeak could be created from another MyArc and upgraded after we returned.
+        // Acquire synchronizes with the Release decrement in `Drop for MyWeak`.
+        if this.inner().weak.compare_exchange(1, usize::MAX, atomic::Ordering::Acquire, atomic::Ordering::Relaxed).is_ok() {
+            // Acquire synchronizes with the Release decrement in `Drop for MyArc`, so all the uses
+            // of the data by the other owners happen before our mutation.
+            let unique = this.inner().rc.load(atomic::Ordering::Acquire) == 1;
+            this.inner().weak.store(1, atomic::Ordering::Release);
+            unique
+        } else {
+            false
+        }
+    }
+
+    fn inner(&self) -> &ArcInner<T, C> {
+        // The allocation is valid as long as there is a MyArc alive.
+        unsafe { self.ptr.as_ref() }
+    }
+
+    // The end of `Drop for MyArc`, once rc reached 0 and the fence made the other handles' uses
+    // visible: drops the data and the weak held by all the MyArc. src/epoch.rs calls it for a whole
+    // bag after a single fence.
+    unsafe fn drop_slow(&mut self) {
+        ptr::drop_in_place(&mut (*self.ptr.as_ptr()).data);
+        // Borrow our allocator, it is dropped along with self.
+        drop(WeakPtr { ptr: self.ptr, alloc: &self.alloc });
+    }
+
+    unsafe fn from_inner_in(ptr: *mut ArcInner<T, C>, alloc: A) -> Self {
+        SharedPtr {
+            ptr: NonNull::new_unchecked(ptr),
+            _marker: PhantomData,
+            alloc
+        }
+    }
+
+    /// Consumes the `MyArc` without decreasing rc and returns a pointer to the data, which can be
+    /// passed around as e.g. a `void*` and turned back with `from_raw`.
+    ///
+    /// The allocator is dropped, use `into_raw_with_allocator` to keep it.
+    pub fn into_raw(this: Self) -> *const T {
+        SharedPtr::into_raw_with_allocator(this).0
+    }
+
+    /// Same as `into_raw`, also giving back the allocator to pass to `from_raw_in`.
+    pub fn into_raw_with_allocator(this: Self) -> (*const T, A) {
+        let this = ManuallyDrop::new(this);
+        let ptr = SharedPtr::as_ptr(&this);
+        (ptr, unsafe { ptr::read(&this.alloc) })
+    }
+
+    // Same as `into_raw_with_allocator`, carrying the type of counter to `__from_raw_parts` so that
+    // `unsize_my_arc!` works for both MyArc and MyRc.
+    #[doc(hidden)]
+    pub fn __into_raw_parts(this: Self) -> (*const T, A, PhantomData<C>) {
+        let (ptr, alloc) = SharedPtr::into_raw_with_allocator(this);
+        (ptr, alloc, PhantomData)
+    }
+
+    #[doc(hidden)]
+    pub unsafe fn __from_raw_parts(ptr: *const T, alloc: A, _: PhantomData<C>) -> Self {
+        SharedPtr::from_raw_in(ptr, alloc)
+    }
+
+    /// Returns a pointer to the data, valid as long as there is a `MyArc` alive.
+    pub fn as_ptr(this: &Self) -> *const T {
+        // Not through `inner()`, so that the pointer keeps the provenance of the whole allocation.
+        unsafe { ptr::addr_of_mut!((*this.ptr.as_ptr()).data) }
+    }
+
+    /// Same as `from_raw`, for a `MyArc` allocated with `alloc`.
+    ///
+    /// # Safety
+    ///
+    /// Same as `from_raw`, and the data must have been allocated by `alloc` or a clone of it.
+    pub unsafe fn from_raw_in(ptr: *const T, alloc: A) -> Self {
+        let offset = data_offset(ptr);
+        // The header sits right before the data, step back to it keeping the length or vtable.
+        SharedPtr::from_inner_in(set_data_ptr(ptr as *mut ArcInner<T, C>, (ptr as *mut u8).sub(offset)), alloc)
+    }
+}
+
+impl<T, C: Count> SharedPtr<[T], C> {
+    /// Creates a `MyArc` to a slice of `len` uninitialized items, see `new_uninit`.
+    pub fn new_uninit_slice(len: usize) -> SharedPtr<[MaybeUninit<T>], C> {
+        unsafe { SharedPtr::from_inner(SharedPtr::<[MaybeUninit<T>], C>::allocate_for_slice(len)) }
+    }
+
+    /// Same as `FromIterator`, but returns an error instead of aborting if the memory for the
+    /// items or for the `MyArc` can't be allocated.
+    pub fn try_from_iter<I: IntoIterator<Item = T>>(iter: I) -> Result<Self, AllocError> {
+        // Same as `FromIterator`, except that the Vec is grown by hand so that its allocations
+        // can fail too.
+        let iter = iter.into_iter();
+        let mut v = Vec::new();
+        v.try_reserve(iter.size_hint().0).map_err(|_| AllocError)?;
+        for item in iter {
+            if v.len() == v.capacity() {
+                v.try_reserve(1).map_err(|_| AllocError)?;
+            }
+            v.push(item);
+        }
+
+        unsafe {
+            let inner = Self::try_allocate_for_slice(v.len())?;
+            Ok(Self::from_vec_in(v, inner))
+        }
+    }
+
+    unsafe fn allocate_for_slice(len: usize) -> *mut ArcInner<[T], C> {
+        Self::allocate_for_layout(Layout::array::<T>(len).unwrap(), |layout| Global.allocate(layout), |mem| {
+            ptr::slice_from_raw_parts_mut(mem as *mut T, len) as *mut ArcInner<[T], C>
+        })
+    }
+
+    unsafe fn try_allocate_for_slice(len: usize) -> Result<*mut ArcInner<[T], C>, AllocError> {
+        let value_layout = Layout::array::<T>(len).map_err(|_| AllocError)?;
+        Self::try_allocate_for_layout(value_layout, |layout| Global.allocate(layout), |mem| {
+            ptr::slice_from_raw_parts_mut(mem as *mut T, len) as *mut ArcInner<[T], C>
+        })
+    }
+
+    /// Moves the items of `v` into `inner`, which must have been allocated for `v.len()` items.
+    unsafe fn from_vec_in(mut v: Vec<T>, inner: *mut ArcInner<[T], C>) -> Self {
+        ptr::copy_nonoverlapping(v.as_ptr(), ptr::addr_of_mut!((*inner).data) as *mut T, v.len());
+        // The items are moved, only free the buffer of v.
+        v.set_len(0);
+        Self::from_inner(inner)
+    }
+
+    /// Moves `len` items out of `iter` into a new allocation. If `iter` panics, the items written so
+    /// far are dropped and the memory is freed.
+    unsafe fn from_iter_exact(iter: impl Iterator<Item = T>, len: usize) -> SharedPtr<[T], C> {
+        // Cleans up a partially written slice when the iterator or a clone panics.
+        struct Guard<T> {
+            mem: NonNull<u8>,
+            layout: Layout,
+            elems: *mut T,
+            n_elems: usize,
+        }
+
+        impl<T> Drop for Guard<T> {
+            fn drop(&mut self) {
+                unsafe {
+                    ptr::drop_in_place(ptr::slice_from_raw_parts_mut(self.elems, self.n_elems));
+                    #[cfg(feature = "track")]
+                    track::unregister(self.mem.as_ptr());
+                    Global.deallocate(self.mem, self.layout);
+                }
+            }
+        }
+
+        let inner = Self::allocate_for_slice(len);
+        let mem = NonNull::new_unchecked(inner as *mut u8);
+        // Not Layout::for_value, which would need a reference to the uninitialized items.
+        let layout = arcinner_layout_for_value_layout(Layout::array::<T>(len).unwrap());
+        let elems = ptr::addr_of_mut!((*inner).data) as *mut T;
+        let mut guard = Guard { mem, layout, elems, n_elems: 0 };
+
+        for (i, item) in iter.take(len).enumerate() {
+            ptr::write(elems.add(i), item);
+            guard.n_elems += 1;
+        }
+        assert_eq!(guard.n_elems, len, "iterator shorter than its reported length");
+
+        // All initialized, the allocation now belongs to the MyArc.
+        std::mem::forget(guard);
+        Self::from_inner(inner)
+    }
+}
+
+impl<T, C: Count, A: Allocator> SharedPtr<MaybeUninit<T>, C, A> {
+    /// Turns the handle into a `MyArc<T>`, once the data has been written.
+    ///
+    /// # Safety
+    ///
+    /// The data must be initialized, as with `MaybeUninit::assume_init`.
+    pub unsafe fn assume_init(self) -> SharedPtr<T, C, A> {
+        let this = ManuallyDrop::new(self);
+        // MaybeUninit<T> has the layout of T, the counts are carried over as they are.
+        let inner = this.ptr.as_ptr() as *mut ArcInner<T, C>;
+        #[cfg(feature = "track")]
+        track::retype(inner);
+        SharedPtr::from_inner_in(inner, ptr::read(&this.alloc))
+    }
+}
+
+impl<T, C: Count, A: Allocator> SharedPtr<[MaybeUninit<T>], C, A> {
+    /// Turns the handle into a `MyArc<[T]>`, once every item has been written.
+    ///
+    /// # Safety
+    ///
+    /// All the items must be initialized, as with `MaybeUninit::assume_init`.
+    pub unsafe fn assume_init(self) -> SharedPtr<[T], C, A> {
+        let this = ManuallyDrop::new(self);
+        let inner = this.ptr.as_ptr() as *mut ArcInner<[T], C>;
+        #[cfg(feature = "track")]
+        track::retype(inner);
+        SharedPtr::from_inner_in(inner, ptr::read(&this.alloc))
+    }
+}
+
+impl<T: Clone, C: Count, A: Allocator + Clone> SharedPtr<T, C, A> {
+    /// Returns a mutable reference to the data, cloning it into a new allocation first if it is
+    /// shared (copy-on-write).
+    ///
+    /// Other `MyArc` keep pointing to the old value. If only `MyWeak` are left, the data is moved
+    /// into a new allocation instead and those `MyWeak` can no longer be upgraded.
+    pub fn make_mut(this: &mut Self) -> &mut T {
+        // Set rc to 0 so that no MyWeak can upgrade while we are deciding, Acquire for the same
+        // reason as in `is_unique`.
+        if this.inner().rc.compare_exchange(1, 0, atomic::Ordering::Acquire, atomic::Ordering::Relaxed).is_err() {
+            // There are other MyArc, clone the data. Dropping the old handle just decreases rc.
+            *this = SharedPtr::new_in((**this).clone(), this.alloc.clone());
+        } else if this.inner().weak.load(atomic::Ordering::Relaxed) != 1 {
+            // rc is already 0 so only MyWeak are left. Move the data out and leave them behind.
+            unsafe {
+                let data = ptr::read(&this.inner().data);
+                let fresh = SharedPtr::new_in(data, this.alloc.clone());
+                // Can't let the old handle drop as its rc is 0, the weak reference it held on
+                // behalf of all MyArc is released through a MyWeak instead.
+                let old = ManuallyDrop::new(mem::replace(this, fresh));
+                drop(WeakPtr { ptr: old.ptr, alloc: ptr::read(&old.alloc) });
+            }
+        } else {
+            // We were the only reference, restore rc.
+            this.inner().rc.store(1, atomic::Ordering::Release);
+        }
+
+        // Either way we are unique now.
+        unsafe { &mut (*this.ptr.as_ptr()).data }
+    }
+}
+
+// loom's AtomicUsize is a handle to a value tracked by the model, not the value itself, so the
+// Cell<usize> counts of a MyRc can't be reinterpreted as one.
+#[cfg(not(loom))]
+impl<T: ?Sized, A: Allocator> MyRc<T, A> {
+    /// Turns a `MyRc` into a `MyArc` without copying the data, if it is the only handle to it
+    /// (no other `MyRc` nor any `MyRcWeak`), otherwise gives it back.
+    pub fn into_arc(this: Self) -> Result<MyArc<T, A>, Self> {
+        // MyRc is !Send, so nobody can change the counts while we look at them.
+        if this.inner().rc.get() != 1 || this.inner().weak.get() != 1 {
+            return Err(this);
+        }
+
+        // The counts are the same in both, and Cell<usize> has the layout of AtomicUsize.
+        let this = ManuallyDrop::new(this);
         unsafe {
-            (*inner).rc.load(atomic::Ordering::Acquire)
+            let inner = this.ptr.as_ptr() as *mut ArcInner<T, AtomicUsize>;
+            #[cfg(feature = "track")]
+            track::retype(inner);
+            Ok(SharedPtr::from_inner_in(inner, ptr::read(&this.alloc)))
+        }
+    }
+}
+
+impl<T, C: Count> WeakPtr<T, C> {
+    /// Creates a `MyWeak` that never upgrades, without allocating anything.
+    pub fn new() -> Self {
+        WeakPtr {
+            ptr: NonNull::new(usize::MAX as *mut ArcInner<T, C>).unwrap(),
+            alloc: Global
+        }
+    }
+}
+
+impl<T: ?Sized, C: Count, A: Allocator> WeakPtr<T, C, A> {
+    /// Attempts to get a `MyArc` back, returns `None` if the data has already been dropped.
+    pub fn upgrade(&self) -> Option<SharedPtr<T, C, A>>
+    where
+        A: Clone
+    {
+        let inner = self.inner()?;
+        // We can't use fetch_add here as rc must never be brought back from 0.
+        let mut n = inner.rc.load(atomic::Ordering::Relaxed);
+        loop {
+            if n == 0 {
+                return None;
+            }
+            if n >= MAX_REFCOUNT {
+                // The data is immortal, no need to count the new handle.
+                overflow::on_overflow_no_increment(&inner.rc);
+                return Some(unsafe { SharedPtr::from_inner_in(self.ptr.as_ptr(), self.alloc.clone()) });
+            }
+            // Acquire on success so that we see the data written by whoever published it.
+            match inner.rc.compare_exchange_weak(n, n + 1, atomic::Ordering::Acquire, atomic::Ordering::Relaxed) {
+                Ok(_) => return Some(unsafe { SharedPtr::from_inner_in(self.ptr.as_ptr(), self.alloc.clone()) }),
+                Err(old) => n = old,
+            }
+        }
+    }
+
+    fn is_dangling(&self) -> bool {
+        self.ptr.as_ptr() as *mut () as usize == usize::MAX
+    }
+
+    fn inner(&self) -> Option<&ArcInner<T, C>> {
+        if self.is_dangling() {
+            None
+        } else {
+            // The data may be dropped already, but the counters are valid as long as weak > 0.
+            Some(unsafe { self.ptr.as_ref() })
         }
     }
 }
 
-impl<T> Deref for MyArc<T> {
+impl<T, C: Count> Default for WeakPtr<T, C> {
+    fn default() -> Self {
+        WeakPtr::new()
+    }
+}
+
+impl<T: ?Sized, C: Count, A: Allocator> Deref for SharedPtr<T, C, A> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -49,94 +842,1338 @@ impl<T> Deref for MyArc<T> {
     }
 }
 
-impl<T> DerefMut for MyArc<T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        let inner = unsafe  { self.ptr.as_mut() };
-        &mut inner.data
-    }
-}
-
-impl<T> Clone for MyArc<T> {
+impl<T: ?Sized, C: Count, A: Allocator + Clone> Clone for SharedPtr<T, C, A> {
+    #[cfg_attr(feature = "track", track_caller)]
     fn clone(&self) -> Self {
         let inner = unsafe { self.ptr.as_ref() };
         // use Ordering::Relaxed because we don't need any synchronization.
         let old_rc = inner.rc.fetch_add(1, atomic::Ordering::Relaxed);
         // In the case that someone cloned MyArc then use std::mem::forget to forget it without
         // running the destructor(decrease rc), the memory will be overflowed. So a threshold is
-        // necessary.
-        if old_rc >= isize::MAX as usize {
-            std::process::abort();
+        // necessary, what happens past it depends on the overflow policy.
+        if old_rc >= MAX_REFCOUNT {
+            overflow::on_overflow(&inner.rc);
         }
+        #[cfg(feature = "track")]
+        track::cloned(self.ptr.as_ptr());
 
         Self {
             ptr: self.ptr,
-            _marker: PhantomData
+            _marker: PhantomData,
+            alloc: self.alloc.clone()
         }
     }
 }
 
+impl<T: ?Sized, C: Count, A: Allocator + Clone> Clone for WeakPtr<T, C, A> {
+    fn clone(&self) -> Self {
+        if let Some(inner) = self.inner() {
+            let old_weak = inner.weak.fetch_add(1, atomic::Ordering::Relaxed);
+            // Same threshold as `Clone for MyArc`.
+            if old_weak >= MAX_REFCOUNT {
+                overflow::on_overflow(&inner.weak);
+            }
+        }
+
+        WeakPtr { ptr: self.ptr, alloc: self.alloc.clone() }
+    }
+}
+
 /// 1. decrease rc if it is greater than 1
 /// 2. if rc equals to 1(only one reference remaining)
 ///     - 1. set a barrier to prevernt reorder of use and deletion of the data
 ///     - 2. drop inner data
-impl<T> Drop for MyArc<T> {
+///     - 3. release the weak reference held by all MyArc, which frees the memory if no MyWeak left
+impl<T: ?Sized, C: Count, A: Allocator> Drop for SharedPtr<T, C, A> {
     fn drop(&mut self) {
-        let inner = unsafe { self.ptr.as_ref() };
-        if (*inner).rc.fetch_sub(1, atomic::Ordering::Release) != 1 {
+        let old_rc = self.inner().rc.fetch_sub(1, atomic::Ordering::Release);
+        if old_rc != 1 {
+            // A saturated rc never goes down, so the data is leaked.
+            overflow::keep_saturated(&self.inner().rc, old_rc);
+            return;
+        }
+        C::fence(atomic::Ordering::Acquire);
+
+        unsafe { self.drop_slow() };
+    }
+}
+
+/// Same protocol as `Drop for MyArc`, except that the data is already gone by the time weak reaches
+/// 0, so only the memory is freed.
+impl<T: ?Sized, C: Count, A: Allocator> Drop for WeakPtr<T, C, A> {
+    fn drop(&mut self) {
+        let inner = match self.inner() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let old_weak = inner.weak.fetch_sub(1, atomic::Ordering::Release);
+        if old_weak != 1 {
+            overflow::keep_saturated(&inner.weak, old_weak);
             return;
         }
-        atomic::fence(atomic::Ordering::Acquire);
+        C::fence(atomic::Ordering::Acquire);
 
         unsafe {
-            Box::from_raw(self.ptr.as_ptr());
+            // The data is dropped but its length or vtable is still there to compute the layout.
+            let layout = Layout::for_value(self.ptr.as_ref());
+            #[cfg(feature = "track")]
+            track::unregister(self.ptr.as_ptr() as *const u8);
+            self.alloc.deallocate(self.ptr.cast(), layout);
         }
     }
 }
 
-impl<T> Drop for ArcInner<T> {
-    fn drop(&mut self) {
+impl<T, C: Count> From<Vec<T>> for SharedPtr<[T], C> {
+    fn from(v: Vec<T>) -> Self {
+        unsafe {
+            let inner = Self::allocate_for_slice(v.len());
+            Self::from_vec_in(v, inner)
+        }
+    }
+}
 
+impl<T: Clone, C: Count> From<&[T]> for SharedPtr<[T], C> {
+    fn from(v: &[T]) -> Self {
+        unsafe { Self::from_iter_exact(v.iter().cloned(), v.len()) }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::MyArc;
-    #[test]
-    fn test_new() {
-        let a = MyArc::new(1);
-        assert!(std::mem::size_of_val(&a) != 0)
+impl<C: Count> From<&str> for SharedPtr<str, C> {
+    fn from(v: &str) -> Self {
+        let bytes = SharedPtr::<[u8], C>::from(v.as_bytes());
+        // str has the same layout as [u8].
+        let this = ManuallyDrop::new(bytes);
+        unsafe {
+            let inner = this.ptr.as_ptr() as *mut ArcInner<str, C>;
+            #[cfg(feature = "track")]
+            track::retype(inner);
+            SharedPtr::from_inner_in(inner, Global)
+        }
     }
+}
 
-    #[test]
-    fn test_deref() {
-        let a = MyArc::new(1);
-        assert_eq!(*a, 1);
+impl<C: Count> From<String> for SharedPtr<str, C> {
+    fn from(v: String) -> Self {
+        SharedPtr::from(&v[..])
     }
+}
 
-    #[test]
-    fn test_deref_mut() {
-        let mut a = MyArc::new(1);
-        *a = 3;
-        assert_eq!(*a, 3);
+impl<T: ?Sized, C: Count> From<Box<T>> for SharedPtr<T, C> {
+    fn from(b: Box<T>) -> Self {
+        unsafe {
+            let value_size = std::mem::size_of_val(&*b);
+            let bptr = Box::into_raw(b);
+            let inner = Self::allocate_for_layout(
+                Layout::for_value(&*bptr),
+                |layout| Global.allocate(layout),
+                |mem| set_data_ptr(bptr as *mut ArcInner<T, C>, mem)
+            );
+            ptr::copy_nonoverlapping(bptr as *const u8, ptr::addr_of_mut!((*inner).data) as *mut u8, value_size);
+
+            // The value is moved, only free the memory of the box.
+            drop(Box::from_raw(bptr as *mut ManuallyDrop<T>));
+            Self::from_inner(inner)
+        }
     }
+}
 
-    #[test]
-    fn test_clone() {
-        let a = MyArc::new(0);
-        assert_eq!(a.count(), 1);
-        let b = a.clone();
-        assert_eq!(a.count(), 2);
+impl<T, C: Count> FromIterator<T> for SharedPtr<[T], C> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        // We need the exact length before allocating, collecting into a Vec is the simplest way.
+        iter.into_iter().collect::<Vec<T>>().into()
     }
+}
 
-    #[test]
-    fn test_drop() {
-        let a = MyArc::new(2);
-        assert_eq!(a.count(), 1);
-        let b = a.clone();
-        assert_eq!(a.count(), 2);
-        drop(a);
-        assert_eq!(b.count(), 1);
+impl<T, C: Count> From<T> for SharedPtr<T, C> {
+    fn from(data: T) -> Self {
+        SharedPtr::new(data)
+    }
+}
+
+impl<T: Default, C: Count> Default for SharedPtr<T, C> {
+    fn default() -> Self {
+        SharedPtr::new(T::default())
+    }
+}
+
+impl<T, C: Count> Default for SharedPtr<[T], C> {
+    fn default() -> Self {
+        SharedPtr::from(Vec::new())
+    }
+}
+
+impl<C: Count> Default for SharedPtr<str, C> {
+    fn default() -> Self {
+        SharedPtr::from("")
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, C: Count, A: Allocator> fmt::Debug for SharedPtr<T, C, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display, C: Count, A: Allocator> fmt::Display for SharedPtr<T, C, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+// The address of the data, which is also what `into_raw` returns.
+impl<T: ?Sized, C: Count, A: Allocator> fmt::Pointer for SharedPtr<T, C, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Pointer::fmt(&SharedPtr::as_ptr(self), f)
+    }
+}
+
+// The data may already be dropped, so there is nothing to print.
+impl<T: ?Sized, C: Count, A: Allocator> fmt::Debug for WeakPtr<T, C, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("(MyWeak)")
+    }
+}
+
+// Always compares the data, without the pointer check `Arc` does for `T: Eq`: picking the impl by
+// `Eq` needs specialization, and a `T: PartialEq` like f64 (NaN != NaN) must not take it. The check
+// is `MyArc::eq_fast` instead.
+impl<T: ?Sized + PartialEq, C: Count, A: Allocator> PartialEq for SharedPtr<T, C, A> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: ?Sized + Eq, C: Count, A: Allocator> Eq for SharedPtr<T, C, A> {}
+
+impl<T: ?Sized + PartialOrd, C: Count, A: Allocator> PartialOrd for SharedPtr<T, C, A> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+
+    fn lt(&self, other: &Self) -> bool {
+        **self < **other
     }
 
+    fn le(&self, other: &Self) -> bool {
+        **self <= **other
+    }
+
+    fn gt(&self, other: &Self) -> bool {
+        **self > **other
+    }
+
+    fn ge(&self, other: &Self) -> bool {
+        **self >= **other
+    }
+}
+
+impl<T: ?Sized + Ord, C: Count, A: Allocator> Ord for SharedPtr<T, C, A> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Hash, C: Count, A: Allocator> Hash for SharedPtr<T, C, A> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T: ?Sized, C: Count, A: Allocator> Borrow<T> for SharedPtr<T, C, A> {
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized, C: Count, A: Allocator> AsRef<T> for SharedPtr<T, C, A> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized + Error, C: Count, A: Allocator> Error for SharedPtr<T, C, A> {
+    #[allow(deprecated)]
+    fn description(&self) -> &str {
+        (**self).description()
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        (**self).source()
+    }
+}
+
+// Iterates over the data, e.g. the items of a MyArc<[T]> or a MyArc<Vec<T>>.
+impl<'a, T: ?Sized, C: Count, A: Allocator> IntoIterator for &'a SharedPtr<T, C, A>
+where
+    &'a T: IntoIterator
+{
+    type Item = <&'a T as IntoIterator>::Item;
+    type IntoIter = <&'a T as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        (**self).into_iter()
+    }
+}
+
+// The IO impls forward to &T, like `Read for Arc<File>`. Only for the handles whose position is
+// kept by the OS and shared by all the references: `&[u8]` is a reader too, but a read through it
+// always starts from the first byte since the slice can't be advanced.
+macro_rules! impl_io_for_shared {
+    ($($t:ty: $($trait:ident),+;)*) => {
+        $($(impl_io_for_shared!(@$trait $t);)+)*
+    };
+    (@Read $t:ty) => {
+        impl<C: Count, A: Allocator> io::Read for SharedPtr<$t, C, A> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                (&**self).read(buf)
+            }
+
+            fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+                (&**self).read_vectored(bufs)
+            }
+
+            fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+                (&**self).read_to_end(buf)
+            }
+
+            fn read_to_string(&mut self, buf: &mut String) -> io::Result<usize> {
+                (&**self).read_to_string(buf)
+            }
+        }
+    };
+    (@Write $t:ty) => {
+        impl<C: Count, A: Allocator> io::Write for SharedPtr<$t, C, A> {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                (&**self).write(buf)
+            }
+
+            fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+                (&**self).write_vectored(bufs)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                (&**self).flush()
+            }
+        }
+    };
+    (@Seek $t:ty) => {
+        impl<C: Count, A: Allocator> io::Seek for SharedPtr<$t, C, A> {
+            fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+                (&**self).seek(pos)
+            }
+        }
+    };
+}
+
+impl_io_for_shared! {
+    std::fs::File: Read, Write, Seek;
+    std::net::TcpStream: Read, Write;
+}
+
+#[cfg(unix)]
+impl_io_for_shared! {
+    std::os::unix::net::UnixStream: Read, Write;
+}
+
+/// Converts a `MyArc<T>` into a `MyArc<U>` when `T` can be unsized into `U`, e.g. to a trait object
+/// or from an array to a slice. This is what `CoerceUnsized` does for `Arc`, which is not stable.
+/// It works the same for a `MyRc`.
+///
+/// ```
+/// use my_arc::{MyArc, unsize_my_arc};
+/// use std::fmt::Display;
+///
+/// let a: MyArc<dyn Display> = unsize_my_arc!(MyArc::new(1) => dyn Display);
+/// assert_eq!(a.to_string(), "1");
+/// ```
+#[macro_export]
+macro_rules! unsize_my_arc {
+    ($arc:expr => $ty:ty) => {{
+        // The unsizing is the implicit coercion of the raw pointer, so the compiler checks it.
+        let (ptr, alloc, count) = $crate::SharedPtr::__into_raw_parts($arc);
+        let ptr: *const $ty = ptr;
+        unsafe { $crate::SharedPtr::__from_raw_parts(ptr, alloc, count) }
+    }};
+}
+
+fn arcinner_layout_for_value_layout(layout: Layout) -> Layout {
+    // Same computation as the compiler does for a repr(C) ArcInner<T>.
+    Layout::new::<ArcInner<()>>().extend(layout).unwrap().0.pad_to_align()
+}
+
+// Offset of data in ArcInner, which only depends on its alignment thanks to repr(C).
+unsafe fn data_offset<T: ?Sized>(ptr: *const T) -> usize {
+    // The data may be unsized, its alignment is read from the vtable behind ptr.
+    let align = std::mem::align_of_val(&*ptr);
+    let header = Layout::new::<ArcInner<()>>().size();
+    (header + align - 1) & !(align - 1)
+}
+
+// Replaces the address of a possibly fat pointer, keeping its length or vtable.
+unsafe fn set_data_ptr<T: ?Sized>(mut ptr: *mut T, data: *mut u8) -> *mut T {
+    ptr::write(&mut ptr as *mut *mut T as *mut *mut u8, data);
+    ptr
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use crate::{ByAddress, MyArc, MyRc, MyWeak, MAX_REFCOUNT};
+    use std::sync::atomic::Ordering;
+    #[test]
+    fn test_new() {
+        let a = MyArc::new(1);
+        assert!(std::mem::size_of_val(&a) != 0)
+    }
+
+    #[test]
+    fn test_deref() {
+        let a = MyArc::new(1);
+        assert_eq!(*a, 1);
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut a = MyArc::new(1);
+        *MyArc::get_mut(&mut a).unwrap() = 3;
+        assert_eq!(*a, 3);
+
+        let b = a.clone();
+        assert!(MyArc::get_mut(&mut a).is_none());
+        drop(b);
+
+        let w = MyArc::downgrade(&a);
+        assert!(MyArc::get_mut(&mut a).is_none());
+        drop(w);
+        assert!(MyArc::get_mut(&mut a).is_some());
+    }
+
+    #[test]
+    fn test_make_mut() {
+        let mut a = MyArc::new(1);
+        *MyArc::make_mut(&mut a) = 2;
+        assert_eq!(*a, 2);
+
+        // Shared, a gets its own copy.
+        let b = a.clone();
+        *MyArc::make_mut(&mut a) = 3;
+        assert_eq!(*a, 3);
+        assert_eq!(*b, 2);
+        assert_eq!(MyArc::strong_count(&a), 1);
+        assert_eq!(MyArc::strong_count(&b), 1);
+
+        // Only a weak left, the data is moved and the weak is disassociated.
+        let w = MyArc::downgrade(&b);
+        let mut b = b;
+        *MyArc::make_mut(&mut b) = 4;
+        assert_eq!(*b, 4);
+        assert!(w.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_clone() {
+        let a = MyArc::new(0);
+        assert_eq!(MyArc::strong_count(&a), 1);
+        let _b = a.clone();
+        assert_eq!(MyArc::strong_count(&a), 2);
+    }
+
+    #[test]
+    fn test_drop() {
+        let a = MyArc::new(2);
+        assert_eq!(MyArc::strong_count(&a), 1);
+        let b = a.clone();
+        assert_eq!(MyArc::strong_count(&a), 2);
+        drop(a);
+        assert_eq!(MyArc::strong_count(&b), 1);
+    }
+
+    #[test]
+    fn test_weak_upgrade() {
+        let a = MyArc::new(String::from("data"));
+        let w = MyArc::downgrade(&a);
+        let b = w.upgrade().unwrap();
+        assert_eq!(*b, "data");
+        assert_eq!(MyArc::strong_count(&a), 2);
+        drop(a);
+        drop(b);
+        assert!(w.upgrade().is_none());
+        assert!(w.clone().upgrade().is_none());
+    }
+
+    #[test]
+    fn test_weak_drops_data_at_zero_strong() {
+        struct Flag(std::rc::Rc<std::cell::Cell<bool>>);
+        impl Drop for Flag {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+
+        let dropped = std::rc::Rc::new(std::cell::Cell::new(false));
+        let a = MyArc::new(Flag(dropped.clone()));
+        let w = MyArc::downgrade(&a);
+        drop(a);
+        // The data is gone even though the allocation is still held by w.
+        assert!(dropped.get());
+        assert!(w.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_unsized_str() {
+        let a: MyArc<str> = MyArc::from("hello");
+        let b: MyArc<str> = MyArc::from(String::from("hello"));
+        assert_eq!(&*a, "hello");
+        assert_eq!(&*a, &*b);
+        let w = MyArc::downgrade(&a);
+        assert_eq!(&*w.upgrade().unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_unsized_slice() {
+        let a: MyArc<[String]> = MyArc::from(vec![String::from("a"), String::from("b")]);
+        assert_eq!(a.len(), 2);
+        assert_eq!(a[1], "b");
+
+        let b: MyArc<[String]> = MyArc::from(&a[..]);
+        assert_eq!(&*a, &*b);
+
+        let c: MyArc<[u32]> = (0..4).collect();
+        assert_eq!(&*c, &[0, 1, 2, 3]);
+
+        let empty: MyArc<[u64]> = MyArc::from(Vec::new());
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_unsized_from_box() {
+        let a: MyArc<dyn Fn() -> i32> = MyArc::from(Box::new(|| 7) as Box<dyn Fn() -> i32>);
+        assert_eq!(a(), 7);
+
+        let b: MyArc<[String]> = MyArc::from(vec![String::from("x")].into_boxed_slice());
+        assert_eq!(b[0], "x");
+    }
+
+    #[test]
+    fn test_unsize_macro() {
+        use std::fmt::Display;
+        let a: MyArc<dyn Display> = crate::unsize_my_arc!(MyArc::new(String::from("s")) => dyn Display);
+        let b = a.clone();
+        assert_eq!(b.to_string(), "s");
+
+        let c: MyArc<[i32]> = crate::unsize_my_arc!(MyArc::new([1, 2, 3]) => [i32]);
+        assert_eq!(&*c, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_from_iter_drops_on_panic() {
+        let items = [String::from("a"), String::from("b")];
+        let result = std::panic::catch_unwind(|| {
+            unsafe { MyArc::from_iter_exact(items.iter().cloned().chain(std::iter::once_with(|| panic!())), 3) }
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_try_unwrap() {
+        let a = MyArc::new(String::from("a"));
+        let b = a.clone();
+        let a = MyArc::try_unwrap(a).unwrap_err();
+        drop(b);
+        assert_eq!(MyArc::try_unwrap(a).ok().unwrap(), "a");
+
+        // A weak doesn't prevent it, but can't be upgraded afterwards.
+        let a = MyArc::new(1);
+        let w = MyArc::downgrade(&a);
+        assert_eq!(MyArc::try_unwrap(a).ok(), Some(1));
+        assert!(w.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let a = MyArc::new(String::from("a"));
+        let b = a.clone();
+        assert_eq!(MyArc::into_inner(a), None);
+        assert_eq!(MyArc::into_inner(b).as_deref(), Some("a"));
+
+        for _ in 0..100 {
+            let a = MyArc::new(String::from("race"));
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    let a = a.clone();
+                    std::thread::spawn(move || MyArc::into_inner(a))
+                })
+                .collect();
+            let mut winners: Vec<_> = handles.into_iter().filter_map(|h| h.join().unwrap()).collect();
+            winners.extend(MyArc::into_inner(a));
+            assert_eq!(winners, vec![String::from("race")]);
+        }
+    }
+
+    #[test]
+    fn test_unwrap_or_clone() {
+        let a = MyArc::new(String::from("a"));
+        let b = a.clone();
+        assert_eq!(MyArc::unwrap_or_clone(a), "a");
+        assert_eq!(MyArc::strong_count(&b), 1);
+        assert_eq!(MyArc::unwrap_or_clone(b), "a");
+    }
+
+    #[test]
+    fn test_raw() {
+        let a = MyArc::new(String::from("raw"));
+        let p = MyArc::into_raw(a.clone());
+        assert_eq!(p, MyArc::as_ptr(&a));
+        assert_eq!(unsafe { &*p }, "raw");
+
+        // Through a void* as a C callback would.
+        let void = p as *mut std::ffi::c_void;
+        let b = unsafe { MyArc::from_raw(void as *const String) };
+        assert_eq!(*b, "raw");
+        assert_eq!(MyArc::strong_count(&a), 2);
+
+        let s: MyArc<str> = MyArc::from("unsized");
+        let s = unsafe { MyArc::from_raw(MyArc::into_raw(s)) };
+        assert_eq!(&*s, "unsized");
+    }
+
+    #[test]
+    fn test_strong_count_adjust() {
+        let a = MyArc::new(5u8);
+        let p = MyArc::into_raw(a.clone());
+        unsafe {
+            MyArc::increment_strong_count(p);
+            assert_eq!(MyArc::strong_count(&a), 3);
+            MyArc::decrement_strong_count(p);
+            assert_eq!(MyArc::strong_count(&a), 2);
+            MyArc::decrement_strong_count(p);
+        }
+        assert_eq!(MyArc::strong_count(&a), 1);
+    }
+
+    #[test]
+    fn test_new_cyclic() {
+        struct Node {
+            me: MyWeak<Node>,
+            value: i32,
+        }
+
+        let a = MyArc::new_cyclic(|weak| {
+            assert!(weak.upgrade().is_none());
+            assert!(weak.clone().upgrade().is_none());
+            Node { me: weak.clone(), value: 1 }
+        });
+        let b = a.me.upgrade().unwrap();
+        assert_eq!(b.value, 1);
+        assert_eq!(MyArc::strong_count(&a), 2);
+
+        let w = a.me.clone();
+        drop(a);
+        drop(b);
+        assert!(w.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_new_cyclic_panic() {
+        let result = std::panic::catch_unwind(|| {
+            MyArc::<String>::new_cyclic(|_| panic!("no data"))
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_new_uninit() {
+        let mut a = MyArc::<String>::new_uninit();
+        MyArc::get_mut(&mut a).unwrap().write(String::from("later"));
+        let a = unsafe { a.assume_init() };
+        assert_eq!(*a, "later");
+
+        let mut s = MyArc::<[String]>::new_uninit_slice(3);
+        for (i, item) in MyArc::get_mut(&mut s).unwrap().iter_mut().enumerate() {
+            item.write(i.to_string());
+        }
+        let s = unsafe { s.assume_init() };
+        assert_eq!(&*s, &["0", "1", "2"]);
+    }
+
+    #[test]
+    fn test_new_zeroed_large() {
+        // Far bigger than the stack of this thread, so it only passes if nothing is built there.
+        const SIZE: usize = 16 << 20;
+        let handle = std::thread::Builder::new()
+            .stack_size(64 << 10)
+            .spawn(|| {
+                let a = unsafe { MyArc::<[u8; SIZE]>::new_zeroed().assume_init() };
+                assert_eq!(a[0], 0);
+                assert_eq!(a[SIZE - 1], 0);
+            })
+            .unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_try_new() {
+        let a = MyArc::try_new(String::from("a")).ok().unwrap();
+        assert_eq!(*a, "a");
+
+        let mut b = MyArc::<u64>::try_new_uninit().ok().unwrap();
+        MyArc::get_mut(&mut b).unwrap().write(3);
+        assert_eq!(unsafe { *b.assume_init() }, 3);
+
+        let c = MyArc::try_from_iter((0..100).map(|i| i.to_string())).ok().unwrap();
+        assert_eq!(c.len(), 100);
+        assert_eq!(c[99], "99");
+    }
+
+    // Counts the blocks it has handed out and not got back yet.
+    struct CountingAlloc {
+        live: std::sync::atomic::AtomicUsize,
+        fail: bool,
+    }
+
+    impl CountingAlloc {
+        fn new(fail: bool) -> Self {
+            CountingAlloc { live: std::sync::atomic::AtomicUsize::new(0), fail }
+        }
+
+        fn live(&self) -> usize {
+            self.live.load(std::sync::atomic::Ordering::Relaxed)
+        }
+    }
+
+    unsafe impl crate::Allocator for CountingAlloc {
+        fn allocate(&self, layout: std::alloc::Layout) -> Result<std::ptr::NonNull<u8>, crate::AllocError> {
+            if self.fail {
+                return Err(crate::AllocError);
+            }
+            self.live.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            crate::Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: std::ptr::NonNull<u8>, layout: std::alloc::Layout) {
+            self.live.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+            crate::Global.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn test_new_in() {
+        let alloc = CountingAlloc::new(false);
+        let a = MyArc::new_in(String::from("in"), &alloc);
+        assert_eq!(alloc.live(), 1);
+        let b = a.clone();
+        assert_eq!(alloc.live(), 1);
+
+        // The weak keeps the block until it is dropped.
+        let w = MyArc::downgrade(&a);
+        drop(a);
+        drop(b);
+        assert_eq!(alloc.live(), 1);
+        drop(w);
+        assert_eq!(alloc.live(), 0);
+    }
+
+    #[test]
+    fn test_try_new_in() {
+        let failing = CountingAlloc::new(true);
+        assert!(MyArc::try_new_in(1, &failing).is_err());
+
+        let alloc = CountingAlloc::new(false);
+        let mut a = MyArc::try_new_in(1, &alloc).ok().unwrap();
+        let b = a.clone();
+        // The copy made by make_mut comes from the same allocator.
+        *MyArc::make_mut(&mut a) = 2;
+        assert_eq!(alloc.live(), 2);
+        assert_eq!((*a, *b), (2, 1));
+        assert_eq!(MyArc::try_unwrap(b).ok(), Some(1));
+        assert_eq!(alloc.live(), 1);
+        drop(a);
+        assert_eq!(alloc.live(), 0);
+    }
+
+    #[test]
+    fn test_raw_in() {
+        let alloc = CountingAlloc::new(false);
+        let a = MyArc::new_in([1u8, 2, 3], &alloc);
+        let a: MyArc<[u8], _> = crate::unsize_my_arc!(a => [u8]);
+        assert_eq!(&*a, &[1, 2, 3]);
+        assert!(std::ptr::eq(*MyArc::allocator(&a), &alloc));
+        drop(a);
+        assert_eq!(alloc.live(), 0);
+    }
+
+    #[test]
+    fn test_try_clone() {
+        let a = MyArc::new(1);
+        let b = a.try_clone().unwrap();
+        assert_eq!(MyArc::strong_count(&a), 2);
+        drop(b);
+
+        a.inner().rc.store(MAX_REFCOUNT, Ordering::Relaxed);
+        assert!(a.try_clone().is_none());
+        assert_eq!(MyArc::strong_count(&a), MAX_REFCOUNT);
+        a.inner().rc.store(1, Ordering::Relaxed);
+    }
+
+    #[cfg(all(unix, not(any(feature = "overflow-panic", feature = "overflow-saturate"))))]
+    #[test]
+    fn test_overflow_abort() {
+        use std::os::unix::process::ExitStatusExt;
+
+        // An abort can't be caught, so the overflow is done by a copy of this test in a child process.
+        if std::env::var_os("MY_ARC_OVERFLOW_CHILD").is_some() {
+            let a = MyArc::new(1);
+            a.inner().rc.store(MAX_REFCOUNT, Ordering::Relaxed);
+            let _b = a.clone();
+            unreachable!("clone past MAX_REFCOUNT returned");
+        }
+
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::test_overflow_abort", "--test-threads=1", "--nocapture"])
+            .env("MY_ARC_OVERFLOW_CHILD", "1")
+            .status()
+            .unwrap();
+        assert_eq!(status.signal(), Some(6), "child should have been aborted: {}", status);
+    }
+
+    #[cfg(all(feature = "overflow-panic", not(feature = "overflow-saturate")))]
+    #[test]
+    fn test_overflow_panic() {
+        let a = MyArc::new(1);
+        a.inner().rc.store(MAX_REFCOUNT, Ordering::Relaxed);
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| a.clone())).is_err());
+        // The increment is undone.
+        assert_eq!(MyArc::strong_count(&a), MAX_REFCOUNT);
+
+        let w = MyArc::downgrade(&a);
+        a.inner().weak.store(MAX_REFCOUNT + 1, Ordering::Relaxed);
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| w.clone())).is_err());
+        // The weak paths overflow at the same count as rc.
+        a.inner().weak.store(MAX_REFCOUNT, Ordering::Relaxed);
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| w.clone())).is_err());
+        assert_eq!(a.inner().weak.load(Ordering::Relaxed), MAX_REFCOUNT);
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| MyArc::downgrade(&a))).is_err());
+        a.inner().rc.store(MAX_REFCOUNT, Ordering::Relaxed);
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| w.upgrade())).is_err());
+
+        a.inner().weak.store(2, Ordering::Relaxed);
+        a.inner().rc.store(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "overflow-saturate")]
+    #[test]
+    fn test_overflow_saturate() {
+        use crate::overflow::SATURATED;
+
+        let a = MyArc::new(String::from("immortal"));
+        a.inner().rc.store(MAX_REFCOUNT, Ordering::Relaxed);
+        let b = a.clone();
+        assert_eq!(MyArc::strong_count(&a), SATURATED);
+
+        // Neither clones, drops nor upgrades move a saturated count.
+        let c = b.clone();
+        drop(b);
+        drop(c);
+        let w = MyArc::downgrade(&a);
+        drop(w.upgrade().unwrap());
+        assert_eq!(MyArc::strong_count(&a), SATURATED);
+        assert!(a.try_clone().is_none());
+
+        // Same for the weak count.
+        a.inner().weak.store(MAX_REFCOUNT + 1, Ordering::Relaxed);
+        let w2 = w.clone();
+        drop(w2);
+        assert_eq!(a.inner().weak.load(Ordering::Relaxed), SATURATED);
+        // Reaching exactly MAX_REFCOUNT saturates too, whether by clone or downgrade.
+        a.inner().weak.store(MAX_REFCOUNT, Ordering::Relaxed);
+        drop(w.clone());
+        assert_eq!(a.inner().weak.load(Ordering::Relaxed), SATURATED);
+        a.inner().weak.store(MAX_REFCOUNT, Ordering::Relaxed);
+        drop(MyArc::downgrade(&a));
+        drop(w);
+        assert_eq!(a.inner().weak.load(Ordering::Relaxed), SATURATED);
+
+        // Put sane counts back so that the test doesn't leak.
+        a.inner().weak.store(1, Ordering::Relaxed);
+        a.inner().rc.store(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_rc() {
+        let mut a = MyRc::new(String::from("rc"));
+        let b = a.clone();
+        assert_eq!(MyRc::strong_count(&a), 2);
+        *MyRc::make_mut(&mut a) = String::from("copy");
+        assert_eq!((a.as_str(), b.as_str()), ("copy", "rc"));
+
+        let w = MyRc::downgrade(&b);
+        assert_eq!(*w.upgrade().unwrap(), "rc");
+        drop(b);
+        assert!(w.upgrade().is_none());
+
+        let s: MyRc<str> = MyRc::from("unsized");
+        assert_eq!(&*s, "unsized");
+        let d: MyRc<dyn std::fmt::Display> = crate::unsize_my_arc!(MyRc::new(5) => dyn std::fmt::Display);
+        assert_eq!(d.to_string(), "5");
+    }
+
+    #[test]
+    fn test_rc_into_arc() {
+        let a = MyRc::new(String::from("move"));
+        let b = a.clone();
+        let a = MyRc::into_arc(a).err().unwrap();
+        drop(b);
+        let w = MyRc::downgrade(&a);
+        let a = MyRc::into_arc(a).err().unwrap();
+        drop(w);
+
+        let p = MyRc::as_ptr(&a);
+        let arc = MyRc::into_arc(a).ok().unwrap();
+        // Same allocation, nothing was copied.
+        assert_eq!(MyArc::as_ptr(&arc), p);
+        let handle = std::thread::spawn(move || arc.len());
+        assert_eq!(handle.join().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_pin() {
+        fn assert_unpin<T: Unpin>() {}
+        assert_unpin::<MyArc<std::marker::PhantomPinned>>();
+        assert_unpin::<MyRc<std::marker::PhantomPinned>>();
+
+        let p = MyArc::pin((5, std::marker::PhantomPinned));
+        let q = p.clone();
+        assert_eq!(&p.0 as *const i32, &q.0 as *const i32);
+        let r = MyArc::try_into_pin(MyArc::new(String::from("unpin"))).unwrap();
+        // T: Unpin, so the MyArc can be taken back.
+        assert_eq!(*std::pin::Pin::into_inner(r), "unpin");
+
+        // Other handles would stay unpinned.
+        let a = MyArc::new(std::marker::PhantomPinned);
+        let b = a.clone();
+        let a = MyArc::try_into_pin(a).err().unwrap();
+        drop(b);
+        let w = MyArc::downgrade(&a);
+        let a = MyArc::try_into_pin(a).err().unwrap();
+        drop(w);
+        assert!(MyArc::try_into_pin(a).is_ok());
+    }
+
+    #[test]
+    fn test_fmt() {
+        let a = MyArc::new(String::from("text"));
+        assert_eq!(format!("{:?}", a), "\"text\"");
+        assert_eq!(format!("{}", a), "text");
+        assert_eq!(format!("{:p}", a), format!("{:p}", MyArc::as_ptr(&a)));
+        assert_eq!(format!("{:?}", MyArc::downgrade(&a)), "(MyWeak)");
+    }
+
+    #[test]
+    fn test_cmp() {
+        let (a, b) = (MyArc::new(1), MyArc::new(2));
+        assert_eq!(a, MyArc::new(1));
+        assert_ne!(a, b);
+        assert!(a < b);
+        assert!(a <= b);
+        assert!(b > a);
+        assert!(b >= a);
+        assert_eq!(a.cmp(&b), std::cmp::Ordering::Less);
+        assert_eq!(a.partial_cmp(&a.clone()), Some(std::cmp::Ordering::Equal));
+
+        // Not equal to itself, the data is compared even for the same allocation.
+        let nan = MyArc::new(f64::NAN);
+        assert_ne!(nan, nan.clone());
+
+        // eq_fast only compares the data of different allocations.
+        struct Counting<'a>(&'a std::cell::Cell<usize>);
+        impl PartialEq for Counting<'_> {
+            fn eq(&self, _: &Self) -> bool {
+                self.0.set(self.0.get() + 1);
+                true
+            }
+        }
+        impl Eq for Counting<'_> {}
+        let compared = std::cell::Cell::new(0);
+        let c = MyRc::new(Counting(&compared));
+        assert!(MyRc::eq_fast(&c, &c.clone()));
+        assert_eq!(compared.get(), 0);
+        assert!(MyRc::eq_fast(&c, &MyRc::new(Counting(&compared))));
+        assert_eq!(compared.get(), 1);
+        assert!(c == c.clone());
+        assert_eq!(compared.get(), 2);
+
+        let set: std::collections::BTreeSet<_> = IntoIterator::into_iter([b.clone(), a.clone(), b]).collect();
+        assert_eq!(set.into_iter().map(|x| *x).collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[test]
+    fn test_hash_borrow() {
+        use std::{borrow::Borrow, collections::HashMap};
+
+        let mut map = HashMap::new();
+        map.insert(MyArc::<str>::from("key"), 1);
+        // Borrow<str>, with the same hash as the str.
+        assert_eq!(map.get("key"), Some(&1));
+        let key: &str = map.keys().next().unwrap().borrow();
+        assert_eq!(key, "key");
+
+        let a = MyArc::new(vec![1, 2]);
+        let v: &Vec<i32> = a.as_ref();
+        assert_eq!(v.len(), 2);
+    }
+
+    #[test]
+    fn test_default_from() {
+        assert_eq!(*MyArc::<i32>::default(), 0);
+        assert!(MyArc::<[i32]>::default().is_empty());
+        assert_eq!(&*MyArc::<str>::default(), "");
+        assert_eq!(*MyArc::from(5), 5);
+        let rc: MyRc<String> = String::from("rc").into();
+        assert_eq!(*rc, "rc");
+    }
+
+    #[test]
+    fn test_error() {
+        use std::error::Error;
+
+        #[derive(Debug)]
+        struct Outer(std::io::Error);
+        impl std::fmt::Display for Outer {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("outer")
+            }
+        }
+        impl Error for Outer {
+            fn source(&self) -> Option<&(dyn Error + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        let e = MyArc::new(Outer(std::io::Error::other("inner")));
+        assert_eq!(e.source().unwrap().to_string(), "inner");
+        let boxed: Box<dyn Error> = Box::new(e);
+        assert_eq!(boxed.to_string(), "outer");
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let a: MyArc<[i32]> = MyArc::from(vec![1, 2, 3]);
+        let mut sum = 0;
+        for x in &a {
+            sum += x;
+        }
+        assert_eq!(sum, 6);
+        assert_eq!((&MyArc::new(vec![4, 5])).into_iter().count(), 2);
+    }
+
+    #[test]
+    fn test_io() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let path = std::env::temp_dir().join(format!("my_arc_test_io_{}", std::process::id()));
+        let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let mut a = MyArc::new(file);
+        // Another handle to the same file, the position is shared.
+        let mut b = a.clone();
+        a.write_all(b"hello").unwrap();
+        a.flush().unwrap();
+        b.seek(SeekFrom::Start(1)).unwrap();
+        let mut s = String::new();
+        a.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "ello");
+        drop((a, b));
+        std::fs::remove_file(path).unwrap();
+
+        #[cfg(unix)]
+        {
+            let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+            let (mut a, mut b) = (MyArc::new(a), MyArc::new(b));
+            a.write_all(b"abc").unwrap();
+            let mut buf = [0; 3];
+            b.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"abc");
+        }
+    }
+
+    #[test]
+    fn test_weak_new() {
+        let w: MyWeak<i32> = MyWeak::new();
+        assert!(w.upgrade().is_none());
+        assert!(w.clone().upgrade().is_none());
+    }
+
+    #[test]
+    fn test_counts() {
+        let mut a = MyArc::new(String::from("a"));
+        assert!(MyArc::is_unique(&mut a));
+        assert_eq!((MyArc::strong_count(&a), MyArc::weak_count(&a)), (1, 0));
+
+        let b = a.clone();
+        let w = MyArc::downgrade(&a);
+        assert_eq!((MyArc::strong_count(&a), MyArc::weak_count(&a)), (2, 1));
+        #[allow(deprecated)]
+        let count = a.count();
+        assert_eq!(count, 2);
+        assert!(!MyArc::is_unique(&mut a));
+        assert!(MyArc::ptr_eq(&a, &b));
+        assert!(!MyArc::ptr_eq(&a, &MyArc::new(String::from("a"))));
+
+        // The other handle is dropped on another thread, is_unique must see what it did before.
+        std::thread::spawn(move || drop(b)).join().unwrap();
+        assert!(!MyArc::is_unique(&mut a));
+        drop(w);
+        assert!(MyArc::is_unique(&mut a));
+        assert_eq!((MyArc::strong_count(&a), MyArc::weak_count(&a)), (1, 0));
+
+        // ptr_eq ignores the length of slices.
+        let s: MyArc<[i32]> = MyArc::from(vec![1, 2]);
+        assert!(MyArc::ptr_eq(&s, &s.clone()));
+    }
+
+    #[test]
+    fn test_by_address() {
+        use std::collections::{BTreeSet, HashMap};
+
+        let (a, b) = (MyArc::new(1), MyArc::new(1));
+        assert_eq!(ByAddress(a.clone()), ByAddress(a.clone()));
+        // Equal data, different allocations.
+        assert_ne!(ByAddress(a.clone()), ByAddress(b.clone()));
+
+        let mut map = HashMap::new();
+        map.insert(ByAddress(a.clone()), "a");
+        map.insert(ByAddress(b.clone()), "b");
+        assert_eq!(map[&ByAddress(a.clone())], "a");
+        assert_eq!(map[&ByAddress(b.clone())], "b");
+
+        let set: BTreeSet<_> = IntoIterator::into_iter([a.clone(), b, a]).map(ByAddress).collect();
+        assert_eq!(set.len(), 2);
+        assert!(set.iter().all(|a| *a.0 == 1));
+    }
+
+    #[cfg(feature = "track")]
+    #[test]
+    fn test_track() {
+        use crate::track;
+        use std::sync::Mutex;
+
+        // Everything freed is forgotten, whichever way it was allocated.
+        let n = track::assert_no_leaks(|| {
+            let a = MyArc::new(1);
+            drop(a.clone());
+            let s: MyArc<[String]> = MyArc::from(vec![String::from("a")]);
+            let z = unsafe { MyArc::<u64>::new_zeroed().assume_init() };
+            let t = crate::ThinArc::from_header_and_iter(0, 0..3);
+            let w = MyArc::downgrade(&s);
+            drop(s);
+            drop(w);
+            *a + *z as i32 + t.slice.len() as i32
+        });
+        assert_eq!(n, 4);
+
+        // A cycle is reported, with its type and where it was created and cloned.
+        struct TrackedNode(Mutex<Option<MyArc<TrackedNode>>>);
+        let result = std::panic::catch_unwind(|| track::assert_no_leaks(|| {
+            let a = MyArc::new(TrackedNode(Mutex::new(None)));
+            *a.0.lock().unwrap() = Some(a.clone());
+        }));
+        let message = *result.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.starts_with("1 MyArc leaked"));
+        assert!(message.contains("TrackedNode"));
+
+        let leaked = track::live_arcs().into_iter().find(|arc| arc.type_name.ends_with("TrackedNode")).unwrap();
+        assert_eq!(leaked.rc, Some(1));
+        assert_eq!(leaked.created.file(), file!());
+        assert_eq!(leaked.last_clone.unwrap().file(), file!());
+        assert_eq!(leaked.thread, std::thread::current().id());
+
+        // The count of a MyRc can't be read from another thread, until it becomes a MyArc.
+        let rc = MyRc::new(String::from("track"));
+        let is_rc = |arc: &track::LiveArc| arc.created.file() == file!() && arc.created.line() == line!() - 1;
+        assert_eq!(track::live_arcs().into_iter().find(is_rc).unwrap().rc, None);
+        let arc = MyRc::into_arc(rc).ok().unwrap();
+        assert_eq!(track::live_arcs().into_iter().find(is_rc).unwrap().rc, Some(1));
+        drop(arc);
+        assert!(!track::live_arcs().iter().any(is_rc));
+
+        // Uninitialized data takes the type it is initialized as.
+        let u = unsafe { MyArc::<u8>::new_zeroed().assume_init() };
+        let is_u = |arc: &track::LiveArc| arc.created.file() == file!() && arc.created.line() == line!() - 1;
+        assert_eq!(track::live_arcs().into_iter().find(is_u).unwrap().type_name, "u8");
+        drop(u);
+
+        // Same for a str, which is allocated as a [u8].
+        let s = MyArc::<str>::from("track");
+        let s2 = s.clone();
+        let is_s = |arc: &track::LiveArc| matches!(arc.last_clone, Some(l) if l.file() == file!() && l.line() == line!() - 1);
+        assert_eq!(track::live_arcs().into_iter().find(is_s).unwrap().type_name, "str");
+        drop((s, s2));
+    }
+}
+
+// Run with `RUSTFLAGS="--cfg loom" cargo test --lib --release`. Each test is a model that loom runs
+// under every interleaving (and every value a Relaxed load may return), the data is in a loom
+// UnsafeCell which fails the model if it is dropped without happening after every read.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use crate::{AllocError, Allocator, ArcInner, Global, MyArc, MyWeak};
+    use loom::{cell::UnsafeCell, sync::{atomic::{AtomicUsize, Ordering}, Arc}, thread};
+    use std::{alloc::Layout, cell::Cell, ptr::NonNull};
+
+    struct Data {
+        value: UnsafeCell<usize>,
+        drops: Arc<AtomicUsize>
+    }
+
+    impl Data {
+        fn new(drops: &Arc<AtomicUsize>) -> Self {
+            Data { value: UnsafeCell::new(1), drops: drops.clone() }
+        }
+
+        fn read(&self) -> usize {
+            self.value.with(|value| unsafe { *value })
+        }
+    }
+
+    impl Drop for Data {
+        fn drop(&mut self) {
+            // A write, which loom checks against the reads of the other threads.
+            self.value.with_mut(|value| unsafe { *value = 0 });
+            self.drops.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Only read through shared references, writes need &mut.
+    unsafe impl Sync for Data {}
+
+    // loom doesn't see the memory being freed, so this writes the value of the (dropped) Data
+    // first, which loom then checks against the last use of the data on the other threads.
+    #[derive(Clone)]
+    struct Poison;
+
+    unsafe impl Allocator for Poison {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            let inner = ptr.cast::<ArcInner<Data>>();
+            (*inner.as_ptr()).data.value.with_mut(|value| *value = usize::MAX);
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn test_loom_clone_drop() {
+        loom::model(|| {
+            let drops = Arc::new(AtomicUsize::new(0));
+            let a = MyArc::new(Data::new(&drops));
+            let b = a.clone();
+            let t = thread::spawn(move || {
+                let c = b.clone();
+                assert_eq!(c.read(), 1);
+                drop(b);
+                assert_eq!(c.read(), 1);
+            });
+            let d = a.clone();
+            drop(a);
+            assert_eq!(d.read(), 1);
+            drop(d);
+            t.join().unwrap();
+            assert_eq!(drops.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    #[test]
+    fn test_loom_last_two_drop() {
+        loom::model(|| {
+            let drops = Arc::new(AtomicUsize::new(0));
+            let a = MyArc::new(Data::new(&drops));
+            let b = a.clone();
+            let t = thread::spawn(move || {
+                assert_eq!(b.read(), 1);
+                drop(b);
+            });
+            assert_eq!(a.read(), 1);
+            drop(a);
+            t.join().unwrap();
+            assert_eq!(drops.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    #[test]
+    fn test_loom_try_unwrap_clone() {
+        loom::model(|| {
+            let drops = Arc::new(AtomicUsize::new(0));
+            let a = MyArc::new(Data::new(&drops));
+            let b = a.clone();
+            // Keeps the allocation alive, so the fence in `Drop for MyWeak` can't make up for a
+            // missing one in try_unwrap.
+            let w = MyArc::downgrade(&a);
+            let t = thread::spawn(move || {
+                let c = b.clone();
+                drop(b);
+                assert_eq!(c.read(), 1);
+            });
+            // Succeeds only once the other thread is done with the data, which we then own.
+            if let Ok(data) = MyArc::try_unwrap(a) {
+                data.value.with_mut(|value| unsafe { *value = 2 });
+            }
+            drop(w);
+            t.join().unwrap();
+            assert_eq!(drops.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    #[test]
+    fn test_loom_upgrade_last_drop() {
+        loom::model(|| {
+            let drops = Arc::new(AtomicUsize::new(0));
+            let a = MyArc::new(Data::new(&drops));
+            let w: MyWeak<Data> = MyArc::downgrade(&a);
+            let t = thread::spawn(move || {
+                if let Some(b) = w.upgrade() {
+                    assert_eq!(b.read(), 1);
+                }
+            });
+            assert_eq!(a.read(), 1);
+            drop(a);
+            t.join().unwrap();
+            assert_eq!(drops.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    #[test]
+    fn test_loom_new_cyclic_upgrade() {
+        loom::model(|| {
+            let drops = Arc::new(AtomicUsize::new(0));
+            let t = Cell::new(None);
+            let a = MyArc::new_cyclic(|w: &MyWeak<Data>| {
+                let w = w.clone();
+                // Upgrades only once the data is written, which it must then see.
+                t.set(Some(thread::spawn(move || {
+                    if let Some(b) = w.upgrade() {
+                        assert_eq!(b.read(), 1);
+                    }
+                })));
+                Data::new(&drops)
+            });
+            drop(a);
+            t.take().unwrap().join().unwrap();
+            assert_eq!(drops.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    #[test]
+    fn test_loom_last_strong_weak_drop() {
+        loom::model(|| {
+            let drops = Arc::new(AtomicUsize::new(0));
+            let a = MyArc::new_in(Data::new(&drops), Poison);
+            let w = MyArc::downgrade(&a);
+            // Either thread can free the memory, after the data is dropped by the other one.
+            let t = thread::spawn(move || drop(w));
+            assert_eq!(a.read(), 1);
+            drop(a);
+            t.join().unwrap();
+            assert_eq!(drops.load(Ordering::Relaxed), 1);
+        });
+    }
 }
@@ -0,0 +1,149 @@
+//! `MappedArc`, a pointer into the data of a `MyArc` that keeps the whole allocation alive, like
+//! the aliasing constructor of C++'s `shared_ptr`.
+
+use crate::{overflow, sync::atomic::{self, AtomicUsize}, ArcInner, MyArc, MAX_REFCOUNT};
+use std::{mem::{self, ManuallyDrop, MaybeUninit}, ops::Deref, ptr::{self, NonNull}};
+
+/// A part of the data of a `MyArc`, e.g. a field or a sub-slice, returned by `MyArc::map`.
+///
+/// Cloning and dropping it updates the rc of the original `MyArc`, which isn't dropped before the
+/// last `MappedArc`. The type of the original data is erased, so it must be `Send + Sync + 'static`.
+pub struct MappedArc<U: ?Sized> {
+    owner: Owner,
+    data: NonNull<U>
+}
+
+// The MyArc the data belongs to, with its type erased.
+struct Owner {
+    rc: NonNull<AtomicUsize>,
+    // The `*const ArcInner<T>` of the MyArc, which may be fat.
+    ptr: MaybeUninit<[usize; 2]>,
+    // Drops the MyArc<T> stored in ptr.
+    drop_arc: unsafe fn(&MaybeUninit<[usize; 2]>)
+}
+
+// The original data is Send + Sync, and the MappedArc only gives access to a &U.
+unsafe impl<U: ?Sized + Sync> Send for MappedArc<U> {}
+unsafe impl<U: ?Sized + Sync> Sync for MappedArc<U> {}
+
+impl<T: ?Sized + Send + Sync + 'static> MyArc<T> {
+    /// Turns the `MyArc` into a pointer to a part of its data, e.g. `MyArc::map(a, |t| &t.field)`.
+    pub fn map<U: ?Sized>(this: Self, f: impl FnOnce(&T) -> &U) -> MappedArc<U> {
+        let data = NonNull::from(f(&this));
+        MappedArc { owner: Owner::new(this), data }
+    }
+
+    /// Same as `map`, giving back the `MyArc` if `f` returns `None`.
+    pub fn try_map<U: ?Sized>(this: Self, f: impl FnOnce(&T) -> Option<&U>) -> Result<MappedArc<U>, Self> {
+        match f(&this).map(NonNull::from) {
+            Some(data) => Ok(MappedArc { owner: Owner::new(this), data }),
+            None => Err(this)
+        }
+    }
+}
+
+impl<U: ?Sized> MappedArc<U> {
+    /// Same as `MyArc::map`, the result still counts in the rc of the original `MyArc`.
+    pub fn map<V: ?Sized>(this: Self, f: impl FnOnce(&U) -> &V) -> MappedArc<V> {
+        let data = NonNull::from(f(&this));
+        MappedArc { owner: MappedArc::into_owner(this), data }
+    }
+
+    /// Same as `MyArc::try_map`.
+    pub fn try_map<V: ?Sized>(this: Self, f: impl FnOnce(&U) -> Option<&V>) -> Result<MappedArc<V>, Self> {
+        match f(&this).map(NonNull::from) {
+            Some(data) => Ok(MappedArc { owner: MappedArc::into_owner(this), data }),
+            None => Err(this)
+        }
+    }
+
+    fn into_owner(this: Self) -> Owner {
+        // Moved out without running Drop, the rc goes with it.
+        let this = ManuallyDrop::new(this);
+        unsafe { ptr::read(&this.owner) }
+    }
+}
+
+impl Owner {
+    fn new<T: ?Sized>(arc: MyArc<T>) -> Owner {
+        unsafe fn drop_arc<T: ?Sized>(ptr: &MaybeUninit<[usize; 2]>) {
+            let inner = ptr::read(ptr.as_ptr() as *const *mut ArcInner<T>);
+            drop(MyArc::from_inner(inner));
+        }
+
+        assert!(mem::size_of::<*mut ArcInner<T>>() <= mem::size_of::<[usize; 2]>(), "pointer to the data too large");
+        let arc = ManuallyDrop::new(arc);
+        let mut ptr = MaybeUninit::<[usize; 2]>::uninit();
+        unsafe { ptr::write(ptr.as_mut_ptr() as *mut *mut ArcInner<T>, arc.ptr.as_ptr()) };
+        Owner { rc: NonNull::from(&arc.inner().rc), ptr, drop_arc: drop_arc::<T> }
+    }
+}
+
+impl<U: ?Sized> Deref for MappedArc<U> {
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        unsafe { self.data.as_ref() }
+    }
+}
+
+impl<U: ?Sized> Clone for MappedArc<U> {
+    fn clone(&self) -> Self {
+        let rc = unsafe { self.owner.rc.as_ref() };
+        // Same as `Clone for MyArc`.
+        let old_rc = rc.fetch_add(1, atomic::Ordering::Relaxed);
+        if old_rc >= MAX_REFCOUNT {
+            overflow::on_overflow(rc);
+        }
+        let owner = Owner { rc: self.owner.rc, ptr: self.owner.ptr, drop_arc: self.owner.drop_arc };
+        MappedArc { owner, data: self.data }
+    }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        unsafe { (self.drop_arc)(&self.ptr) }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use crate::{MappedArc, MyArc};
+
+    #[test]
+    fn test_map() {
+        struct Table {
+            name: String,
+            rows: Vec<u32>
+        }
+
+        let a = MyArc::new(Table { name: String::from("t"), rows: vec![1, 2, 3, 4] });
+        let name = MyArc::map(a.clone(), |t| &t.name);
+        assert_eq!(&**name, "t");
+        assert_eq!(MyArc::strong_count(&a), 2);
+
+        // Nested, still one rc for the two maps.
+        let rows = MyArc::map(a.clone(), |t| &t.rows[..]);
+        let tail = MappedArc::map(rows, |r| &r[2..]);
+        assert_eq!(*tail, [3, 4]);
+        assert_eq!(MyArc::strong_count(&a), 3);
+
+        let c = tail.clone();
+        assert_eq!(MyArc::strong_count(&a), 4);
+        let a = MyArc::try_map(a, |t| t.rows.get(10)).err().unwrap();
+        let c = MappedArc::try_map(c, |r| r.get(10)).err().unwrap();
+        let first = MappedArc::try_map(c, |r| r.first()).ok().unwrap();
+        assert_eq!(*first, 3);
+
+        // The table outlives the MyArc it came from.
+        drop(a);
+        drop(name);
+        drop(tail);
+        assert_eq!(*first, 3);
+
+        // From an unsized MyArc, which is a fat pointer.
+        let s: MyArc<str> = MyArc::from("hello world");
+        let world = MyArc::map(s, |s| &s[6..]);
+        assert_eq!(&*world, "world");
+    }
+}
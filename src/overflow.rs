@@ -0,0 +1,55 @@
+//! What happens when a count goes past `MAX_REFCOUNT`, which can only be reached by leaking
+//! handles, e.g. with `mem::forget` in a loop.
+//!
+//! The policy is chosen with cargo features:
+//! - default: abort the process, like `Arc`.
+//! - `overflow-panic`: undo the increment and panic, so only the thread that overflowed unwinds.
+//! - `overflow-saturate`: pin the count at `SATURATED`, where it never goes down again. The data is
+//!   leaked (immortal) but every handle stays valid.
+//!
+//! If both features end up enabled, e.g. by two crates of the same build, saturating wins.
+
+use crate::{Count, MAX_REFCOUNT};
+use std::sync::atomic;
+
+// Halfway through the range above MAX_REFCOUNT, so that increments and decrements of handles that
+// raced with the saturation can't bring the count back to a valid value before they re-saturate
+// it. It is also far from usize::MAX, which `is_unique` uses to lock the weak count.
+pub(crate) const SATURATED: usize = MAX_REFCOUNT + (usize::MAX - MAX_REFCOUNT) / 2;
+
+/// Called after `count` was incremented past MAX_REFCOUNT.
+#[cold]
+pub(crate) fn on_overflow<C: Count>(count: &C) {
+    if cfg!(feature = "overflow-saturate") {
+        count.store(SATURATED, atomic::Ordering::Relaxed);
+    } else if cfg!(feature = "overflow-panic") {
+        count.fetch_sub(1, atomic::Ordering::Relaxed);
+        panic!("reference count overflow");
+    } else {
+        std::process::abort();
+    }
+}
+
+/// Called when a compare-exchange loop finds the count at MAX_REFCOUNT or past it, before
+/// incrementing it. Only returns once the count is saturated, in which case the new handle isn't
+/// counted at all.
+#[cold]
+pub(crate) fn on_overflow_no_increment<C: Count>(count: &C) {
+    if cfg!(feature = "overflow-saturate") {
+        count.store(SATURATED, atomic::Ordering::Relaxed);
+        return;
+    }
+    if cfg!(feature = "overflow-panic") {
+        panic!("reference count overflow");
+    }
+    std::process::abort();
+}
+
+/// Called after `count` was decremented from `old`, other than 1. If it was saturated the decrement
+/// is undone, so that the count never reaches 0.
+#[inline]
+pub(crate) fn keep_saturated<C: Count>(count: &C, old: usize) {
+    if cfg!(feature = "overflow-saturate") && old > MAX_REFCOUNT {
+        count.store(SATURATED, atomic::Ordering::Relaxed);
+    }
+}
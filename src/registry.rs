@@ -0,0 +1,90 @@
+//! The list of per-thread records of the reclamation schemes (the hazard slots of src/hazard.rs and
+//! the participants of src/epoch.rs), which other threads walk to see what is being read.
+//!
+//! It is lock-free and only ever pushed to. A thread done with an entry releases it, and the next
+//! thread that needs one takes it over instead of pushing a new one. Entries are only freed along
+//! with the registry.
+
+use std::{ops::Deref, ptr, sync::atomic::{self, AtomicBool, AtomicPtr}};
+
+pub(crate) struct Registry<T> {
+    head: AtomicPtr<Entry<T>>
+}
+
+pub(crate) struct Entry<T> {
+    // Whether a thread owns the entry.
+    in_use: AtomicBool,
+    next: *mut Entry<T>,
+    value: T
+}
+
+// Every thread reads the values, and whichever thread drops the registry frees them.
+unsafe impl<T: Send + Sync> Send for Registry<T> {}
+unsafe impl<T: Send + Sync> Sync for Registry<T> {}
+
+impl<T> Registry<T> {
+    pub(crate) const fn new() -> Self {
+        Registry { head: AtomicPtr::new(ptr::null_mut()) }
+    }
+
+    /// Takes an entry that no thread owns, or pushes a new one with the value made by `new`.
+    pub(crate) fn acquire(&self, new: impl FnOnce() -> T) -> &Entry<T> {
+        for entry in self.iter() {
+            if !entry.in_use.load(atomic::Ordering::Relaxed)
+                && entry.in_use.compare_exchange(false, true, atomic::Ordering::Acquire, atomic::Ordering::Relaxed).is_ok() {
+                return entry;
+            }
+        }
+
+        let new = Box::into_raw(Box::new(Entry {
+            in_use: AtomicBool::new(true),
+            next: ptr::null_mut(),
+            value: new()
+        }));
+        let mut head = self.head.load(atomic::Ordering::Relaxed);
+        loop {
+            unsafe { (*new).next = head };
+            // Release so that a thread walking the list sees the initialized entry.
+            match self.head.compare_exchange_weak(head, new, atomic::Ordering::Release, atomic::Ordering::Relaxed) {
+                Ok(_) => return unsafe { &*new },
+                Err(current) => head = current
+            }
+        }
+    }
+
+    /// Every entry, whether a thread owns it or not.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Entry<T>> {
+        let mut entry = self.head.load(atomic::Ordering::Acquire);
+        std::iter::from_fn(move || {
+            let e = unsafe { entry.as_ref() }?;
+            entry = e.next;
+            Some(e)
+        })
+    }
+}
+
+impl<T> Entry<T> {
+    /// Gives the entry back for another thread to take.
+    pub(crate) fn release(&self) {
+        // Release so that what the owner did with the value happens before the next owner.
+        self.in_use.store(false, atomic::Ordering::Release);
+    }
+}
+
+impl<T> Deref for Entry<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> Drop for Registry<T> {
+    fn drop(&mut self) {
+        let mut entry = *self.head.get_mut();
+        while !entry.is_null() {
+            let e = unsafe { Box::from_raw(entry) };
+            entry = e.next;
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! A `MyArc` of any type, waiting for the reclamation schemes (src/hazard.rs and src/epoch.rs) to
+//! decide that no reader can still see it.
+
+use crate::MyArc;
+use std::mem::ManuallyDrop;
+
+/// A `MyArc` with its type erased. It is leaked unless passed to `drop_arc`.
+pub(crate) struct Retired {
+    ptr: *const u8,
+    // MyArc::from_raw for the erased type, and drop.
+    drop_arc: unsafe fn(*const u8)
+}
+
+// The retired MyArc are of Send + Sync types, and are dropped by whichever thread reclaims them.
+unsafe impl Send for Retired {}
+
+impl Retired {
+    pub(crate) fn new<T: Send + Sync + 'static>(arc: MyArc<T>) -> Self {
+        unsafe fn drop_arc<T>(ptr: *const u8) {
+            drop(MyArc::from_raw(ptr as *const T));
+        }
+
+        Retired { ptr: MyArc::into_raw(arc) as *const u8, drop_arc: drop_arc::<T> }
+    }
+
+    /// A `MyArc` whose rc already went from 1 to 0, for `EpochArc`. `drop_arc` then skips the
+    /// fetch_sub and fence of `Drop for MyArc`, and only drops the data and frees.
+    pub(crate) fn released<T: Send + Sync + 'static>(arc: MyArc<T>) -> Self {
+        unsafe fn drop_released<T>(ptr: *const u8) {
+            ManuallyDrop::new(MyArc::from_raw(ptr as *const T)).drop_slow();
+        }
+
+        Retired { ptr: MyArc::into_raw(arc) as *const u8, drop_arc: drop_released::<T> }
+    }
+
+    /// The data pointer of the `MyArc`, as a reader would protect it.
+    pub(crate) fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    /// Drops the `MyArc`.
+    ///
+    /// # Safety
+    ///
+    /// No reader may still use the pointer without holding an rc. For a `released` one, an Acquire
+    /// fence must have run after the fetch_sub that took rc to 0, on the thread that did it, and
+    /// happen before this call.
+    pub(crate) unsafe fn drop_arc(self) {
+        (self.drop_arc)(self.ptr)
+    }
+}
@@ -0,0 +1,174 @@
+//! A reference count split across cache lines, so that threads cloning and dropping the same
+//! pointer mostly don't touch the same line.
+//!
+//! Each thread is assigned a shard (round-robin), and a handle counts in the shard of the thread
+//! that created it, wherever it is dropped. `nonzero` counts the shards that are not 0: a shard
+//! going from 0 to 1 increments it, from 1 to 0 decrements it, and when it reaches 0 there are no
+//! handles left. A clone that takes a shard from 0 to 1 is done by a thread holding a handle in
+//! another shard, which keeps `nonzero` above 0 while the increment catches up, so only the drop
+//! that empties the last shard ever sees it reach 0.
+
+use crate::{overflow, MAX_REFCOUNT};
+use std::{ops::Deref, ptr::NonNull, sync::atomic::{self, AtomicUsize}};
+
+const SHARDS: usize = 16;
+
+// Two lines, since some CPUs prefetch cache lines in pairs.
+#[repr(align(128))]
+struct Shard(AtomicUsize);
+
+/// A reference-counted pointer whose count is spread over `SHARDS` cache lines, for data that many
+/// threads clone and drop at the same time. See src/sharded.rs for how the counts work.
+///
+/// The allocation is larger than a `MyArc` (a few kilobytes), and there are no weak handles.
+pub struct ShardedArc<T> {
+    ptr: NonNull<ShardedInner<T>>,
+    // The shard this handle counts in.
+    shard: usize
+}
+
+struct ShardedInner<T> {
+    shards: [Shard; SHARDS],
+    // Number of shards that are not 0, on its own line too.
+    nonzero: Shard,
+    data: T
+}
+
+// The same bounds as MyArc.
+unsafe impl<T: Send + Sync> Send for ShardedArc<T> {}
+unsafe impl<T: Send + Sync> Sync for ShardedArc<T> {}
+
+thread_local! {
+    static SHARD: usize = next_shard();
+}
+
+fn next_shard() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    NEXT.fetch_add(1, atomic::Ordering::Relaxed) % SHARDS
+}
+
+fn current_shard() -> usize {
+    // Any shard works, e.g. while the thread locals are destroyed.
+    SHARD.try_with(|shard| *shard).unwrap_or(0)
+}
+
+impl<T> ShardedArc<T> {
+    pub fn new(data: T) -> Self {
+        let shard = current_shard();
+        let inner = Box::new(ShardedInner {
+            shards: Default::default(),
+            nonzero: Shard(AtomicUsize::new(1)),
+            data
+        });
+        inner.shards[shard].0.store(1, atomic::Ordering::Relaxed);
+        ShardedArc { ptr: NonNull::from(Box::leak(inner)), shard }
+    }
+
+    fn inner(&self) -> &ShardedInner<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// Returns the number of handles, adding up the shards one after the other.
+    ///
+    /// Unlike `MyArc::count` this is not a value the count had at some point: handles cloned and
+    /// dropped by other threads while the shards are read may be counted or not, in any
+    /// combination. It is exact when no other thread is cloning or dropping handles, e.g. after
+    /// joining them, and never 0 since `this` is counted.
+    pub fn count(this: &Self) -> usize {
+        // Acquire, so that everything that happened before the updates we read is visible.
+        let sum = this.inner().shards.iter()
+            .map(|shard| shard.0.load(atomic::Ordering::Acquire))
+            .fold(0, usize::saturating_add);
+        // Our own handle may have been missed if other threads dropped and cloned in between.
+        std::cmp::max(sum, 1)
+    }
+}
+
+impl<T> Deref for ShardedArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner().data
+    }
+}
+
+impl<T> Clone for ShardedArc<T> {
+    fn clone(&self) -> Self {
+        let shard = current_shard();
+        let count = &self.inner().shards[shard].0;
+        // Relaxed for the same reason as `Clone for MyArc`, we already hold a handle.
+        let old = count.fetch_add(1, atomic::Ordering::Relaxed);
+        if old >= MAX_REFCOUNT {
+            overflow::on_overflow(count);
+        } else if old == 0 {
+            // Our own handle is in another shard (this one was 0), so nonzero can't reach 0 before
+            // this increment.
+            self.inner().nonzero.0.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+        ShardedArc { ptr: self.ptr, shard }
+    }
+}
+
+impl<T> Drop for ShardedArc<T> {
+    fn drop(&mut self) {
+        let inner = self.inner();
+        let count = &inner.shards[self.shard].0;
+        let old = count.fetch_sub(1, atomic::Ordering::Release);
+        if old != 1 {
+            overflow::keep_saturated(count, old);
+            return;
+        }
+        // The drops that only decremented this shard must happen before our decrement of nonzero,
+        // so that whichever thread frees sees all of them.
+        atomic::fence(atomic::Ordering::Acquire);
+        if inner.nonzero.0.fetch_sub(1, atomic::Ordering::Release) != 1 {
+            return;
+        }
+        // Same as `Drop for MyArc`.
+        atomic::fence(atomic::Ordering::Acquire);
+        unsafe { drop(Box::from_raw(self.ptr.as_ptr())) };
+    }
+}
+
+impl Default for Shard {
+    fn default() -> Self {
+        Shard(AtomicUsize::new(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::DropFlag, ShardedArc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_sharded() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let a = ShardedArc::new(DropFlag(&DROPS));
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                let a = &a;
+                s.spawn(move || {
+                    for _ in 0..1_000 {
+                        let held: Vec<_> = (0..4).map(|_| a.clone()).collect();
+                        assert!(ShardedArc::count(&held[0]) >= 2);
+                        // Dropped on another shard than the one they count in.
+                        std::thread::spawn(move || drop(held)).join().unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(ShardedArc::count(&a), 1);
+
+        // The last handle can be in any shard.
+        let b = std::thread::spawn({
+            let a = a.clone();
+            move || a.clone()
+        }).join().unwrap();
+        assert_eq!(ShardedArc::count(&a), 2);
+        drop(a);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+        drop(b);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    }
+}
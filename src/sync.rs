@@ -0,0 +1,9 @@
+//! The atomics the counts are built on. Building with `RUSTFLAGS="--cfg loom"` swaps them for
+//! loom's, so that the model tests at the end of lib.rs check the orderings of `Clone`, `Drop`,
+//! `try_unwrap` and `upgrade` against every interleaving loom explores.
+
+#[cfg(not(loom))]
+pub(crate) use std::{hint, sync::atomic};
+
+#[cfg(loom)]
+pub(crate) use loom::{hint, sync::atomic};
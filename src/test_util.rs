@@ -0,0 +1,54 @@
+//! Fixtures shared by the tests of several modules.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub(crate) struct DropFlag<'a>(pub(crate) &'a AtomicUsize);
+
+impl Drop for DropFlag<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// The values of a stress test, see `Counted`.
+pub(crate) struct DropCounts {
+    created: AtomicUsize,
+    dropped: AtomicUsize
+}
+
+impl DropCounts {
+    pub(crate) const fn new() -> Self {
+        DropCounts { created: AtomicUsize::new(0), dropped: AtomicUsize::new(0) }
+    }
+
+    pub(crate) fn value(&'static self, n: usize) -> Counted {
+        self.created.fetch_add(1, Ordering::Relaxed);
+        Counted { n, dropped: AtomicBool::new(false), counts: self }
+    }
+
+    pub(crate) fn all_dropped(&self) -> bool {
+        self.dropped.load(Ordering::Relaxed) == self.created.load(Ordering::Relaxed)
+    }
+}
+
+// Every value must be dropped exactly once, and a reader must never see a value that was
+// already dropped.
+pub(crate) struct Counted {
+    n: usize,
+    dropped: AtomicBool,
+    counts: &'static DropCounts
+}
+
+impl Counted {
+    pub(crate) fn get(&self) -> usize {
+        assert!(!self.dropped.load(Ordering::Relaxed));
+        self.n
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        assert!(!self.dropped.swap(true, Ordering::Relaxed));
+        self.counts.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,236 @@
+//! `ThinArc`, a `MyArc<HeaderSlice<H, [T]>>` behind a one-word pointer.
+//!
+//! The length of the slice is stored in the allocation, next to the header, instead of in the
+//! pointer. To use the allocation as a `MyArc` again, the fat pointer is rebuilt from that length.
+
+use crate::{arcinner_layout_for_value_layout, Allocator, ArcInner, Global, MyArc};
+use std::{alloc::Layout, marker::PhantomData, mem::ManuallyDrop, ops::Deref, ptr::{self, NonNull}};
+
+/// A header followed by a value, usually a slice, as stored by `ThinArc`.
+#[repr(C)]
+pub struct HeaderSlice<H, T: ?Sized> {
+    pub header: H,
+    // The length of slice, which a ThinArc needs to rebuild the fat pointer. It is private so that
+    // only ThinArc creates a HeaderSlice, and the length is always right.
+    len: usize,
+    pub slice: T
+}
+
+/// A reference-counted header and slice in a single allocation, behind a pointer one word wide.
+///
+/// It is the same allocation as a `MyArc<HeaderSlice<H, [T]>>`, see `from_arc` and `into_arc`.
+pub struct ThinArc<H, T> {
+    // Points to an ArcInner<HeaderSlice<H, [T]>>, whose length is read from the allocation.
+    ptr: NonNull<ArcInner<HeaderSlice<H, [T; 0]>>>,
+    // As if we own a MyArc<HeaderSlice<H, [T]>>.
+    _marker: PhantomData<MyArc<HeaderSlice<H, [T]>>>
+}
+
+// The same bounds as MyArc.
+unsafe impl<H: Send + Sync, T: Send + Sync> Send for ThinArc<H, T> {}
+unsafe impl<H: Send + Sync, T: Send + Sync> Sync for ThinArc<H, T> {}
+
+impl<H, T> ThinArc<H, T> {
+    /// Creates a `ThinArc` with `header` and the items of `items`.
+    ///
+    /// # Panics
+    ///
+    /// If `items` yields fewer items than its reported length.
+    pub fn from_header_and_iter<I>(header: H, items: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: ExactSizeIterator
+    {
+        // Cleans up the header and a partially written slice when the iterator panics.
+        struct Guard<H, T> {
+            mem: NonNull<u8>,
+            layout: Layout,
+            header: *mut H,
+            elems: *mut T,
+            n_elems: usize
+        }
+
+        impl<H, T> Drop for Guard<H, T> {
+            fn drop(&mut self) {
+                unsafe {
+                    ptr::drop_in_place(self.header);
+                    ptr::drop_in_place(ptr::slice_from_raw_parts_mut(self.elems, self.n_elems));
+                    #[cfg(feature = "track")]
+                    crate::track::unregister(self.mem.as_ptr());
+                    Global.deallocate(self.mem, self.layout);
+                }
+            }
+        }
+
+        let items = items.into_iter();
+        let len = items.len();
+        // The layout the compiler gives HeaderSlice<H, [T]> for this length, repr(C) lays the
+        // fields one after the other.
+        let value_layout = Layout::new::<H>()
+            .extend(Layout::new::<usize>()).unwrap().0
+            .extend(Layout::array::<T>(len).unwrap()).unwrap().0
+            .pad_to_align();
+
+        unsafe {
+            let inner = MyArc::<HeaderSlice<H, [T]>>::allocate_for_layout(
+                value_layout,
+                |layout| Global.allocate(layout),
+                |mem| ptr::slice_from_raw_parts_mut(mem as *mut T, len) as *mut ArcInner<HeaderSlice<H, [T]>>
+            );
+            let data = ptr::addr_of_mut!((*inner).data);
+            ptr::write(ptr::addr_of_mut!((*data).header), header);
+            ptr::write(ptr::addr_of_mut!((*data).len), len);
+            let mut guard = Guard {
+                mem: NonNull::new_unchecked(inner as *mut u8),
+                layout: arcinner_layout_for_value_layout(value_layout),
+                header: ptr::addr_of_mut!((*data).header),
+                elems: ptr::addr_of_mut!((*data).slice) as *mut T,
+                n_elems: 0
+            };
+
+            for (i, item) in items.take(len).enumerate() {
+                ptr::write(guard.elems.add(i), item);
+                guard.n_elems += 1;
+            }
+            assert_eq!(guard.n_elems, len, "iterator shorter than its reported length");
+
+            // All initialized, the allocation now belongs to the ThinArc.
+            std::mem::forget(guard);
+            ThinArc::from_arc(MyArc::from_inner(inner))
+        }
+    }
+
+    /// Turns a `MyArc` into a `ThinArc`, without copying.
+    pub fn from_arc(arc: MyArc<HeaderSlice<H, [T]>>) -> Self {
+        let arc = ManuallyDrop::new(arc);
+        // Dropping the length, which the allocation has too.
+        ThinArc { ptr: arc.ptr.cast(), _marker: PhantomData }
+    }
+
+    /// Turns the `ThinArc` back into a `MyArc`, without copying.
+    pub fn into_arc(this: Self) -> MyArc<HeaderSlice<H, [T]>> {
+        let this = ManuallyDrop::new(this);
+        unsafe { MyArc::from_inner(this.fat_ptr()) }
+    }
+
+    /// Calls `f` with the `MyArc` this is, without touching rc.
+    pub fn with_arc<U>(&self, f: impl FnOnce(&MyArc<HeaderSlice<H, [T]>>) -> U) -> U {
+        // The MyArc is only borrowed, the ThinArc keeps the rc it would drop.
+        let arc = ManuallyDrop::new(unsafe { MyArc::from_inner(self.fat_ptr()) });
+        f(&arc)
+    }
+
+    /// Returns a view of the data that is `Copy` and doesn't touch rc.
+    pub fn borrow_arc(&self) -> ThinArcBorrow<'_, H, T> {
+        ThinArcBorrow { ptr: self.ptr, _marker: PhantomData }
+    }
+
+    fn fat_ptr(&self) -> *mut ArcInner<HeaderSlice<H, [T]>> {
+        fat_ptr(self.ptr)
+    }
+}
+
+impl<H, T> Deref for ThinArc<H, T> {
+    type Target = HeaderSlice<H, [T]>;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &(*self.fat_ptr()).data }
+    }
+}
+
+impl<H, T> Clone for ThinArc<H, T> {
+    fn clone(&self) -> Self {
+        ThinArc::from_arc(self.with_arc(MyArc::clone))
+    }
+}
+
+impl<H, T> Drop for ThinArc<H, T> {
+    fn drop(&mut self) {
+        unsafe { drop(MyArc::from_inner(self.fat_ptr())) }
+    }
+}
+
+/// A borrowed `ThinArc`, one word wide too, returned by `ThinArc::borrow_arc`.
+pub struct ThinArcBorrow<'a, H, T> {
+    ptr: NonNull<ArcInner<HeaderSlice<H, [T; 0]>>>,
+    _marker: PhantomData<&'a ThinArc<H, T>>
+}
+
+impl<H, T> ThinArcBorrow<'_, H, T> {
+    /// Returns a new `ThinArc` to the data.
+    pub fn clone_arc(&self) -> ThinArc<H, T> {
+        let arc = ManuallyDrop::new(ThinArc { ptr: self.ptr, _marker: PhantomData });
+        ThinArc::clone(&arc)
+    }
+}
+
+impl<H, T> Clone for ThinArcBorrow<'_, H, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<H, T> Copy for ThinArcBorrow<'_, H, T> {}
+
+impl<H, T> Deref for ThinArcBorrow<'_, H, T> {
+    type Target = HeaderSlice<H, [T]>;
+
+    fn deref(&self) -> &HeaderSlice<H, [T]> {
+        unsafe { &(*fat_ptr(self.ptr)).data }
+    }
+}
+
+// Rebuilds the pointer to the whole allocation from the length stored in it.
+fn fat_ptr<H, T>(ptr: NonNull<ArcInner<HeaderSlice<H, [T; 0]>>>) -> *mut ArcInner<HeaderSlice<H, [T]>> {
+    unsafe {
+        let len = (*ptr.as_ptr()).data.len;
+        // Casting a slice pointer to a pointer to a struct ending with a slice keeps the length.
+        ptr::slice_from_raw_parts_mut(ptr.as_ptr() as *mut T, len) as *mut ArcInner<HeaderSlice<H, [T]>>
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use crate::{test_util::DropFlag, MyArc, ThinArc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_thin_arc() {
+        assert_eq!(std::mem::size_of::<ThinArc<u8, u64>>(), std::mem::size_of::<usize>());
+        assert_eq!(std::mem::size_of::<Option<ThinArc<u8, u64>>>(), std::mem::size_of::<usize>());
+
+        let a = ThinArc::from_header_and_iter(String::from("header"), (0..5).map(|i| i.to_string()));
+        assert_eq!(a.header, "header");
+        assert_eq!(a.slice, ["0", "1", "2", "3", "4"]);
+
+        let b = a.borrow_arc();
+        assert_eq!(b.slice.len(), 5);
+        let c = b.clone_arc();
+        assert_eq!(a.with_arc(MyArc::strong_count), 2);
+
+        let arc = ThinArc::into_arc(c);
+        assert_eq!(arc.slice[4], "4");
+        let c = ThinArc::from_arc(arc);
+        drop(a);
+        assert_eq!(c.with_arc(MyArc::strong_count), 1);
+
+        // A header aligned more than the length, with a byte slice right after the length.
+        let d = ThinArc::from_header_and_iter(7u128, Vec::<u8>::new());
+        assert_eq!((d.header, d.slice.len()), (7, 0));
+        let e = ThinArc::from_header_and_iter(1u128, [1u8, 2, 3]);
+        assert_eq!(e.slice, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_thin_arc_panic() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let items = (0..4).map(|i| {
+            assert!(i < 2);
+            DropFlag(&DROPS)
+        });
+        let result = std::panic::catch_unwind(|| ThinArc::from_header_and_iter(DropFlag(&DROPS), items));
+        assert!(result.is_err());
+        // The header and the two items written.
+        assert_eq!(DROPS.load(Ordering::Relaxed), 3);
+    }
+}
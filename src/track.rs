@@ -0,0 +1,160 @@
+//! A registry of the live `MyArc` allocations, to find the ones that leaked, e.g. through a cycle
+//! or a `mem::forget`. Only built with the `track` feature.
+//!
+//! Every ArcInner is recorded when it is allocated, with the type of its data, where and on which
+//! thread it was created, and a backtrace, and removed when it is freed. `live_arcs` lists what is
+//! left, along with the current rc, and `assert_no_leaks` checks a test for leaks.
+//!
+//! Backtraces are captured with `Backtrace::capture`, so they are only filled in when
+//! `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE` is set.
+
+use crate::{sync::atomic::{self, AtomicUsize}, ArcInner, Count};
+use std::{backtrace::Backtrace, collections::BTreeMap, fmt, panic::Location, sync::{atomic::AtomicU64, Arc, Mutex, MutexGuard, PoisonError}, thread::{self, ThreadId}};
+
+// Keyed by the address of the ArcInner. The entry of an allocation is removed before it is freed,
+// so while the lock is held the rc of every entry can be read.
+static LIVE: Mutex<BTreeMap<usize, Record>> = Mutex::new(BTreeMap::new());
+
+struct Record {
+    type_name: &'static str,
+    // None for MyRc, whose count can't be read from another thread.
+    rc: Option<*const AtomicUsize>,
+    seq: u64,
+    thread: ThreadId,
+    created: &'static Location<'static>,
+    last_clone: Option<&'static Location<'static>>,
+    backtrace: Arc<Backtrace>
+}
+
+// rc is only read with the lock held, while the allocation is alive.
+unsafe impl Send for Record {}
+
+/// A `MyArc` allocation still alive, returned by `live_arcs`.
+#[derive(Clone)]
+pub struct LiveArc {
+    /// The type of the data, as given by `std::any::type_name` when it was created.
+    pub type_name: &'static str,
+    /// rc when `live_arcs` was called, `None` for a `MyRc`.
+    pub rc: Option<usize>,
+    /// The thread that created it.
+    pub thread: ThreadId,
+    /// Where it was created. Only constructors like `new` pass on their caller, the others show a
+    /// location in this crate, see `backtrace` for those.
+    pub created: &'static Location<'static>,
+    /// Where it was last cloned, if it ever was. Drops can't be recorded the same way, since the
+    /// compiler inserts them without a caller location.
+    pub last_clone: Option<&'static Location<'static>>,
+    /// Captured when it was created.
+    pub backtrace: Arc<Backtrace>
+}
+
+impl fmt::Debug for LiveArc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LiveArc")
+            .field("type_name", &self.type_name)
+            .field("rc", &self.rc)
+            .field("thread", &self.thread)
+            .field("created", &self.created)
+            .field("last_clone", &self.last_clone)
+            .finish()
+    }
+}
+
+impl fmt::Display for LiveArc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MyArc<{}> created at {}", self.type_name, self.created)?;
+        match self.rc {
+            Some(rc) => write!(f, ", rc {}", rc)?,
+            None => write!(f, ", single-threaded")?
+        }
+        if let Some(location) = self.last_clone {
+            write!(f, ", last cloned at {}", location)?;
+        }
+        write!(f, "\n{}", self.backtrace)
+    }
+}
+
+/// Returns every `MyArc` (and `MyRc`) allocation that hasn't been freed yet, oldest first.
+pub fn live_arcs() -> Vec<LiveArc> {
+    collect(|_| true)
+}
+
+/// Runs `f` and panics if a `MyArc` the current thread allocated during it is still alive after it,
+/// listing them.
+///
+/// Only the allocations of the current thread are checked, so that tests running in parallel don't
+/// see each other's. A `MyArc` created by a thread that `f` spawns is not checked, nor one dropped
+/// by another thread after `f` returns.
+#[track_caller]
+pub fn assert_no_leaks<R>(f: impl FnOnce() -> R) -> R {
+    let start = next_seq();
+    let result = f();
+    let thread = thread::current().id();
+    let leaked = collect(|record| record.thread == thread && record.seq > start);
+    if !leaked.is_empty() {
+        let list: Vec<_> = leaked.iter().map(LiveArc::to_string).collect();
+        panic!("{} MyArc leaked:\n{}", leaked.len(), list.join("\n"));
+    }
+    result
+}
+
+fn collect(filter: impl Fn(&Record) -> bool) -> Vec<LiveArc> {
+    let live = lock();
+    let mut records: Vec<_> = live.values().filter(|record| filter(record)).collect();
+    records.sort_by_key(|record| record.seq);
+    records.into_iter().map(|record| LiveArc {
+        type_name: record.type_name,
+        rc: record.rc.map(|rc| unsafe { (*rc).load(atomic::Ordering::Relaxed) }),
+        thread: record.thread,
+        created: record.created,
+        last_clone: record.last_clone,
+        backtrace: record.backtrace.clone()
+    }).collect()
+}
+
+fn lock() -> MutexGuard<'static, BTreeMap<usize, Record>> {
+    // A panic while the lock is held can't leave the map half updated.
+    LIVE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn next_seq() -> u64 {
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+    SEQ.fetch_add(1, atomic::Ordering::Relaxed)
+}
+
+/// Records the allocation of `inner`, whose counts are initialized.
+#[track_caller]
+pub(crate) unsafe fn register<T: ?Sized, C: Count>(inner: *const ArcInner<T, C>) {
+    let record = Record {
+        type_name: std::any::type_name::<T>(),
+        rc: (*inner).rc.as_atomic().map(|rc| rc as *const AtomicUsize),
+        seq: next_seq(),
+        thread: thread::current().id(),
+        created: Location::caller(),
+        last_clone: None,
+        backtrace: Arc::new(Backtrace::capture())
+    };
+    lock().insert(inner as *const u8 as usize, record);
+}
+
+/// Updates the type and counter of `inner`, after the allocation was turned into another kind of
+/// `MyArc`, e.g. by `assume_init`.
+pub(crate) unsafe fn retype<T: ?Sized, C: Count>(inner: *const ArcInner<T, C>) {
+    if let Some(record) = lock().get_mut(&(inner as *const u8 as usize)) {
+        record.type_name = std::any::type_name::<T>();
+        record.rc = (*inner).rc.as_atomic().map(|rc| rc as *const AtomicUsize);
+    }
+}
+
+/// Records where `inner` was cloned.
+#[track_caller]
+pub(crate) fn cloned<T: ?Sized, C>(inner: *const ArcInner<T, C>) {
+    if let Some(record) = lock().get_mut(&(inner as *const u8 as usize)) {
+        record.last_clone = Some(Location::caller());
+    }
+}
+
+/// Forgets the allocation at `mem`, right before it is freed.
+pub(crate) fn unregister(mem: *const u8) {
+    lock().remove(&(mem as usize));
+}
@@ -0,0 +1,152 @@
+//! `UniqueArc`, a `MyArc` known to be the only handle, which can be mutated without checks.
+
+use crate::MyArc;
+use std::{mem::MaybeUninit, ops::{Deref, DerefMut}};
+
+/// A `MyArc` with rc == 1 and no `MyWeak`, for building data before sharing it.
+///
+/// There is no way to clone or downgrade it, so it stays unique and derefs mutably. `shareable`
+/// turns it into a `MyArc` for free.
+pub struct UniqueArc<T: ?Sized>(MyArc<T>);
+
+// Owned like a Box, nothing is shared until it becomes a MyArc.
+unsafe impl<T: ?Sized + Send> Send for UniqueArc<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for UniqueArc<T> {}
+
+impl<T> UniqueArc<T> {
+    pub fn new(data: T) -> Self {
+        UniqueArc(MyArc::new(data))
+    }
+
+    /// Same as `MyArc::new_uninit`.
+    pub fn new_uninit() -> UniqueArc<MaybeUninit<T>> {
+        UniqueArc(MyArc::new_uninit())
+    }
+
+    /// Same as `MyArc::new_zeroed`.
+    pub fn new_zeroed() -> UniqueArc<MaybeUninit<T>> {
+        UniqueArc(MyArc::new_zeroed())
+    }
+}
+
+impl<T> UniqueArc<[T]> {
+    /// Same as `MyArc::new_uninit_slice`.
+    pub fn new_uninit_slice(len: usize) -> UniqueArc<[MaybeUninit<T>]> {
+        UniqueArc(MyArc::new_uninit_slice(len))
+    }
+}
+
+impl<T> UniqueArc<MaybeUninit<T>> {
+    /// Same as `MyArc::assume_init`.
+    ///
+    /// # Safety
+    ///
+    /// The data must be initialized, as with `MaybeUninit::assume_init`.
+    pub unsafe fn assume_init(self) -> UniqueArc<T> {
+        UniqueArc(self.0.assume_init())
+    }
+}
+
+impl<T> UniqueArc<[MaybeUninit<T>]> {
+    /// Same as `MyArc::assume_init`.
+    ///
+    /// # Safety
+    ///
+    /// All the items must be initialized, as with `MaybeUninit::assume_init`.
+    pub unsafe fn assume_init(self) -> UniqueArc<[T]> {
+        UniqueArc(self.0.assume_init())
+    }
+}
+
+impl<T: ?Sized> UniqueArc<T> {
+    /// Turns the handle into a `MyArc`, which can then be cloned.
+    pub fn shareable(self) -> MyArc<T> {
+        self.0
+    }
+}
+
+impl<T: ?Sized> MyArc<T> {
+    /// Turns the `MyArc` into a `UniqueArc` if it is the only handle, and there is no `MyWeak`.
+    pub fn try_into_unique(mut this: Self) -> Result<UniqueArc<T>, Self> {
+        // Nobody else can clone or downgrade it afterward, there is nothing left to clone from.
+        if MyArc::get_mut(&mut this).is_some() {
+            Ok(UniqueArc(this))
+        } else {
+            Err(this)
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for UniqueArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: ?Sized> DerefMut for UniqueArc<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Unique since it was created, see try_into_unique.
+        unsafe { &mut (*self.0.ptr.as_ptr()).data }
+    }
+}
+
+impl<T> From<Vec<T>> for UniqueArc<[T]> {
+    fn from(v: Vec<T>) -> Self {
+        UniqueArc(MyArc::from(v))
+    }
+}
+
+impl From<&str> for UniqueArc<str> {
+    fn from(s: &str) -> Self {
+        UniqueArc(MyArc::from(s))
+    }
+}
+
+impl From<String> for UniqueArc<str> {
+    fn from(s: String) -> Self {
+        UniqueArc(MyArc::from(s))
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use crate::{MyArc, UniqueArc};
+
+    #[test]
+    fn test_unique_arc() {
+        let mut u = UniqueArc::new(vec![1]);
+        u.push(2);
+        let a = u.shareable();
+        assert_eq!(*a, [1, 2]);
+
+        let b = a.clone();
+        let a = MyArc::try_into_unique(a).err().unwrap();
+        drop(b);
+        let w = MyArc::downgrade(&a);
+        let a = MyArc::try_into_unique(a).err().unwrap();
+        drop(w);
+        let mut u = MyArc::try_into_unique(a).ok().unwrap();
+        u.clear();
+        assert!(u.shareable().is_empty());
+    }
+
+    #[test]
+    fn test_unique_arc_uninit_unsized() {
+        let mut u = UniqueArc::<u64>::new_uninit();
+        u.write(5);
+        assert_eq!(*unsafe { u.assume_init() }.shareable(), 5);
+
+        let mut u = UniqueArc::<[String]>::new_uninit_slice(2);
+        u[0].write(String::from("a"));
+        u[1].write(String::from("b"));
+        let mut u = unsafe { u.assume_init() };
+        u[1].push('c');
+        assert_eq!(*u.shareable(), ["a", "bc"]);
+
+        let mut s = UniqueArc::from("abc");
+        s.make_ascii_uppercase();
+        assert_eq!(&*s.shareable(), "ABC");
+    }
+}
@@ -0,0 +1,75 @@
+use my_arc::{AllocError, MyArc};
+use std::{alloc::{GlobalAlloc, Layout, System}, cell::Cell, ptr, sync::atomic::{AtomicBool, Ordering}};
+
+// Fails the allocations of the current thread while FAIL is set, so that the test harness running
+// on other threads is not affected.
+struct FailingAlloc;
+
+thread_local! {
+    static FAIL: Cell<bool> = const { Cell::new(false) };
+}
+
+unsafe impl GlobalAlloc for FailingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if FAIL.try_with(|fail| fail.get()).unwrap_or(false) {
+            ptr::null_mut()
+        } else {
+            System.alloc(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: FailingAlloc = FailingAlloc;
+
+fn failing<R>(f: impl FnOnce() -> R) -> R {
+    FAIL.with(|fail| fail.set(true));
+    let result = f();
+    FAIL.with(|fail| fail.set(false));
+    result
+}
+
+#[test]
+fn test_try_new_fails() {
+    let result = failing(|| MyArc::try_new(1u64).err());
+    assert_eq!(result, Some(AllocError));
+
+    // Allocations work again once the allocator recovers.
+    assert_eq!(*MyArc::try_new(1u64).ok().unwrap(), 1);
+}
+
+#[test]
+fn test_try_new_uninit_fails() {
+    let result = failing(|| MyArc::<[u8; 4096]>::try_new_uninit().err());
+    assert_eq!(result, Some(AllocError));
+}
+
+#[test]
+fn test_try_from_iter_fails() {
+    let result = failing(|| MyArc::try_from_iter(0..1000u32).err());
+    assert_eq!(result, Some(AllocError));
+
+    // Even an empty slice needs the header.
+    let result = failing(|| MyArc::try_from_iter(std::iter::empty::<u32>()).err());
+    assert_eq!(result, Some(AllocError));
+}
+
+#[test]
+fn test_try_new_drops_data() {
+    static DROPPED: AtomicBool = AtomicBool::new(false);
+    struct Flag;
+    impl Drop for Flag {
+        fn drop(&mut self) {
+            DROPPED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // The data handed to a failed try_new is dropped rather than leaked.
+    let result = failing(|| MyArc::try_new(Flag).err());
+    assert_eq!(result, Some(AllocError));
+    assert!(DROPPED.load(Ordering::Relaxed));
+}